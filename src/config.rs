@@ -5,6 +5,7 @@ use serde_yaml::Value;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use crate::provisioning;
 
@@ -12,8 +13,8 @@ use crate::provisioning;
 pub struct Config {
     pub services: Services,
 }
-const CONFIG_FILE_PATH: &'static str = "config/config.yaml";
-const EFFECTIVE_CONFIG_FILE_PATH: &'static str = "config/effectiveConfig.yaml";
+const CONFIG_FILE_PATH: &str = "config/config.yaml";
+const EFFECTIVE_CONFIG_FILE_PATH: &str = "config/effectiveConfig.yaml";
 
 pub static CONFIG: OnceCell<Config> = OnceCell::new();
 
@@ -33,8 +34,7 @@ impl Config {
     fn to_effective_config(path: PathBuf) -> Result<(), Error> {
         let content = serde_yaml::to_string(Self::global()).unwrap();
         // println!("effective config is {}", c);
-        let config =
-            fs::write(path, content).expect("Something went wrong writing effective config file");
+        fs::write(path, content).expect("Something went wrong writing effective config file");
         Ok(())
     }
 }
@@ -81,6 +81,222 @@ pub struct Configuration {
     pub iot_role_alias: String,
     #[serde(rename = "runWithDefault")]
     pub run_with_default: Value,
+    #[serde(default)]
+    pub mqtt: Mqtt,
+    #[serde(rename = "networkProxy", default)]
+    pub network_proxy: NetworkProxy,
+    #[serde(rename = "fleetStatus", default)]
+    pub fleet_status: FleetStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FleetStatus {
+    #[serde(
+        rename = "periodicStatusPublishIntervalSeconds",
+        default = "default_periodic_status_publish_interval"
+    )]
+    pub periodic_status_publish_interval_seconds: u64,
+}
+
+impl Default for FleetStatus {
+    fn default() -> Self {
+        FleetStatus {
+            periodic_status_publish_interval_seconds: default_periodic_status_publish_interval(),
+        }
+    }
+}
+
+fn default_periodic_status_publish_interval() -> u64 {
+    86_400
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetworkProxy {
+    /// Comma-separated hosts, domains and IP addresses that are reached without the proxy.
+    #[serde(rename = "noProxyAddresses", default)]
+    pub no_proxy_addresses: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<ProxyConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyConfig {
+    /// `scheme://[username:password@]host[:port]`, where scheme is `http`, `https`, `socks5` or
+    /// `socks5h`.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mqtt {
+    /// Protocol version, `mqtt5` or `mqtt3` for MQTT 3.1.1.
+    #[serde(default)]
+    pub version: MqttVersion,
+    /// MQTT 5 session expiry; 0 starts a clean session on every connect.
+    #[serde(rename = "sessionExpiryIntervalSeconds", default)]
+    pub session_expiry_interval_seconds: u32,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Interval between pings, 0 to disable keep-alive.
+    #[serde(rename = "keepAliveTimeoutMs", default = "default_keep_alive_timeout")]
+    pub keep_alive_timeout_ms: u64,
+    /// Time to wait for a ping response before the connection is considered lost.
+    #[serde(rename = "pingTimeoutMs", default = "default_ping_timeout")]
+    pub ping_timeout_ms: u64,
+    /// Time to wait for a subscribe, unsubscribe or publish to be accepted by the client.
+    #[serde(rename = "operationTimeoutMs", default = "default_operation_timeout")]
+    pub operation_timeout_ms: u64,
+    #[serde(
+        rename = "maxInFlightPublishes",
+        default = "default_max_in_flight_publishes"
+    )]
+    pub max_in_flight_publishes: u16,
+    /// Subscriptions on one connection before another connection is opened.
+    #[serde(
+        rename = "maxSubscriptionsPerConnection",
+        default = "default_max_subscriptions_per_connection"
+    )]
+    pub max_subscriptions_per_connection: usize,
+    #[serde(default)]
+    pub spooler: Spooler,
+    /// Retries for a failed publish before the message is dropped, -1 to retry forever.
+    #[serde(rename = "maxPublishRetry", default = "default_max_publish_retry")]
+    pub max_publish_retry: i64,
+    #[serde(rename = "maxMessageSizeInBytes", default = "default_max_message_size")]
+    pub max_message_size_in_bytes: usize,
+    #[serde(
+        rename = "maxPublishesPerSecond",
+        default = "default_max_publishes_per_second"
+    )]
+    pub max_publishes_per_second: f64,
+    #[serde(
+        rename = "maxPublishBytesPerSecond",
+        default = "default_max_publish_bytes_per_second"
+    )]
+    pub max_publish_bytes_per_second: f64,
+    /// Connect with MQTT over WebSocket on port 443, for networks that only allow HTTPS.
+    #[serde(rename = "useWebSocket", default)]
+    pub use_web_socket: bool,
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Mqtt {
+            version: MqttVersion::default(),
+            session_expiry_interval_seconds: 0,
+            port: default_port(),
+            keep_alive_timeout_ms: default_keep_alive_timeout(),
+            ping_timeout_ms: default_ping_timeout(),
+            operation_timeout_ms: default_operation_timeout(),
+            max_in_flight_publishes: default_max_in_flight_publishes(),
+            max_subscriptions_per_connection: default_max_subscriptions_per_connection(),
+            spooler: Spooler::default(),
+            max_publish_retry: default_max_publish_retry(),
+            max_message_size_in_bytes: default_max_message_size(),
+            max_publishes_per_second: default_max_publishes_per_second(),
+            max_publish_bytes_per_second: default_max_publish_bytes_per_second(),
+            use_web_socket: false,
+        }
+    }
+}
+
+impl Mqtt {
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_millis(self.keep_alive_timeout_ms)
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_millis(self.ping_timeout_ms)
+    }
+
+    pub fn operation_timeout(&self) -> Duration {
+        Duration::from_millis(self.operation_timeout_ms)
+    }
+}
+
+fn default_port() -> u16 {
+    8883
+}
+
+fn default_keep_alive_timeout() -> u64 {
+    60_000
+}
+
+fn default_ping_timeout() -> u64 {
+    30_000
+}
+
+fn default_operation_timeout() -> u64 {
+    30_000
+}
+
+fn default_max_in_flight_publishes() -> u16 {
+    5
+}
+
+// AWS IoT Core allows 50 subscriptions on a single connection.
+fn default_max_subscriptions_per_connection() -> usize {
+    50
+}
+
+fn default_max_publish_retry() -> i64 {
+    100
+}
+
+fn default_max_message_size() -> usize {
+    131_072
+}
+
+// AWS IoT Core allows 100 publishes and 512 KiB per second on a single connection.
+fn default_max_publishes_per_second() -> f64 {
+    100.0
+}
+
+fn default_max_publish_bytes_per_second() -> f64 {
+    524_288.0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MqttVersion {
+    #[serde(rename = "mqtt3")]
+    Mqtt3,
+    #[default]
+    #[serde(rename = "mqtt5")]
+    Mqtt5,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Spooler {
+    #[serde(rename = "storageType", default)]
+    pub storage_type: SpoolerStorageType,
+    #[serde(rename = "maxSizeInBytes", default = "default_spooler_max_size")]
+    pub max_size_in_bytes: usize,
+    #[serde(rename = "keepQos0WhenOffline", default)]
+    pub keep_qos0_when_offline: bool,
+}
+
+impl Default for Spooler {
+    fn default() -> Self {
+        Spooler {
+            storage_type: SpoolerStorageType::default(),
+            max_size_in_bytes: default_spooler_max_size(),
+            keep_qos0_when_offline: false,
+        }
+    }
+}
+
+fn default_spooler_max_size() -> usize {
+    2_621_440
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpoolerStorageType {
+    #[default]
+    Memory,
+    Disk,
 }
 
 // #[cfg(test)]
@@ -91,3 +307,28 @@ pub struct Configuration {
 //         init("./config/config.yaml");
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_mqtt_connection_settings() {
+        let mqtt: Mqtt = serde_yaml::from_str(
+            "port: 443\nkeepAliveTimeoutMs: 30000\npingTimeoutMs: 5000\noperationTimeoutMs: 1500\nmaxInFlightPublishes: 20\n",
+        )
+        .unwrap();
+        assert_eq!(mqtt.port, 443);
+        assert_eq!(mqtt.keep_alive(), Duration::from_secs(30));
+        assert_eq!(mqtt.ping_timeout(), Duration::from_secs(5));
+        assert_eq!(mqtt.operation_timeout(), Duration::from_millis(1500));
+        assert_eq!(mqtt.max_in_flight_publishes, 20);
+
+        let defaults: Mqtt = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(defaults.port, 8883);
+        assert_eq!(defaults.keep_alive(), Duration::from_secs(60));
+        assert_eq!(defaults.ping_timeout(), Duration::from_secs(30));
+        assert_eq!(defaults.operation_timeout(), Duration::from_secs(30));
+        assert_eq!(defaults.max_in_flight_publishes, 5);
+    }
+}
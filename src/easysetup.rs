@@ -4,7 +4,7 @@
 //! with the customer's provided config if desired, optionally provision the test device as an AWS IoT Thing, create and
 //! attach policies and certificates to it, create TES role and role alias or uses existing ones and attaches
 //! them to the IoT thing certificate.
-use crate::{services, Args};
+use crate::{proxy, services, Args};
 
 use super::provisioning;
 use anyhow::{Context, Error, Ok, Result};
@@ -90,7 +90,12 @@ pub async fn downloadRootCAToFile(path: &Path) -> Result<()> {
 
     // TODO: append
 
-    let body = reqwest::get(ROOT_CA_URL).await?.text().await?;
+    let body = proxy::http_client()?
+        .get(ROOT_CA_URL)
+        .send()
+        .await?
+        .text()
+        .await?;
 
     debug!("body = {:?}", &body);
     fs::write(path, body).expect("Unable to write file");
@@ -135,8 +140,8 @@ pub async fn provision(args: &Args) -> Result<()> {
     }
 
     info!("Setting up resources for {name} ...");
-    setupIoTRoleForTes(&role, role_alias, "certificateArn");
-    createAndAttachRolePolicy(&role, &region);
+    setupIoTRoleForTes(role, role_alias, "certificateArn");
+    createAndAttachRolePolicy(role, region);
 
     Ok(())
 }
@@ -168,7 +173,7 @@ async fn updateKernelConfigWithIotConfiguration(name: &str) {
  * @param thing_name  thing_name
  * @return created thing info
  */
-async fn createThing(thing_name: &str, region: &str, policy: &str, root_path: &PathBuf) -> Result<()> {
+async fn createThing(thing_name: &str, region: &str, policy: &str, root_path: &Path) -> Result<()> {
     let region_provider =
         RegionProviderChain::first_try(Region::new(region.to_string())).or_default_provider();
     let shared_config = aws_config::from_env().region(region_provider).load().await;
@@ -195,13 +200,13 @@ async fn createThing(thing_name: &str, region: &str, policy: &str, root_path: &P
         .await?;
     fs::write(
         root_path.join("thingCert.crt"),
-        &keyResponse
+        keyResponse
             .certificate_pem()
             .context("Failed to create certificate for thing.")?,
     )?;
     fs::write(
         root_path.join("privKey.key"),
-        &keyResponse
+        keyResponse
             .key_pair
             .as_ref()
             .unwrap()
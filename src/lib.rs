@@ -3,13 +3,15 @@ pub mod config;
 pub mod dependency;
 pub mod easysetup;
 pub mod mqtt;
+pub mod platform;
 pub mod provisioning;
+pub mod proxy;
 pub mod util;
 
 pub mod services;
 
 // pub use self::easysetup::perform_setup;
-pub use self::mqtt::publish;
+pub use self::mqtt::MqttClient;
 pub use self::services::kernel::VERSION as ggcVersion;
 
 use clap::Parser;
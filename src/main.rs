@@ -1,15 +1,15 @@
+use std::sync::Arc;
+
 use anyhow::{Error, Result};
 use aws_greengrass_nucleus::{
-    config, easysetup, mqtt,
-    services::{self, deployment},
+    config, easysetup,
+    mqtt::{self, spool::Spool, ConnectionState, MqttClient},
+    services::{self, alts::Alternatives, deployment, jobs, kernel},
     Args,
 };
-use aws_iot_device_sdk::{shadow, *};
 use clap::Parser;
-use rumqttc::{self, Event, Packet, Publish};
-use tokio::sync::mpsc;
-use tracing::info;
-use tracing_subscriber;
+use tokio::sync::watch;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -21,47 +21,29 @@ async fn main() -> Result<(), Error> {
     }
     easysetup::setup(&args).await;
     config::init(&args.init_config)?;
-    let (mqtt_client, mut eventloop) = mqtt::init(&args.thing_name)?;
-    let (tx, mut rx) = mpsc::channel(128);
+    match Alternatives::new(&args.root).setup() {
+        Ok(true) => kernel::restart(kernel::REQUEST_RESTART),
+        Ok(false) => {}
+        Err(e) => warn!("Failed to set up the nucleus launch directory: {:#}", e),
+    }
+    let (client, eventloop) = mqtt::init(&args.thing_name)?;
+
+    let mqtt_config = &config::Config::global().services.kernel.configuration.mqtt;
+    let spool = Arc::new(Spool::new(&mqtt_config.spooler, &args.root)?);
+    let (state_tx, state) = watch::channel(ConnectionState::Disconnected);
+    let mqtt_client = MqttClient::new(&args.thing_name, client, spool, state, mqtt_config.clone());
 
     info!("Launching Nucleus...");
-    services::start_services(tx.clone()).await?;
+    services::start_services(mqtt_client.clone()).await?;
     info!("Launched Nucleus successfully.");
     deployment::connect_shadow(&mqtt_client, &args.thing_name).await?;
-    while args.start {
-        tokio::select! {
-            Ok(event) = eventloop.poll() => { process(event, tx.clone()).await; }
-            Some(msg) = rx.recv() => {
-                let mqtt_client = mqtt_client.clone();
-                tokio::spawn(async move {
-                    println!("publish to {}",msg.topic);
-                    mqtt_client.publish(msg.topic, msg.qos, false, msg.payload).await.unwrap();
-                    // update effectiveConfig.yaml?
-                });
-            }
-        }
+    jobs::connect(&mqtt_client, &args.thing_name).await?;
+    if args.start {
+        mqtt::connection::run(eventloop, state_tx, mqtt_client).await;
     }
     Ok(())
 }
 
-async fn process(event: Event, tx: mpsc::Sender<Publish>) {
-    println!("{:?}", event);
-    if let Event::Incoming(Packet::Publish(v)) = event {
-        match match_topic_type(&v.topic) {
-            Ok(TopicType::NamedShadow) => {
-                if shadow::match_topic(&v.topic).unwrap().shadow_op == shadow::Topic::UpdateDelta {
-                    tokio::spawn(async move {
-                        deployment::shadow_deployment(v, tx).await.unwrap();
-                    });
-                }
-            }
-            Ok(TopicType::Jobs) => {}
-            _ => {}
-        }
-    }
-    // Ok(())
-}
-
 #[allow(unused)]
 #[cfg(test)]
 mod test {
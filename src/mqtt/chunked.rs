@@ -0,0 +1,68 @@
+//! Chunking of large JSON payloads.
+//!
+//! Some messages, such as fleet status updates, carry a list that can grow past the AWS IoT Core
+//! message size limit. They are sent as several messages, each made of the common fields and a
+//! slice of the list.
+
+use std::mem;
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing::warn;
+
+/// Split `items` into chunks whose JSON encoding, added to a payload of `base_size` bytes
+/// without the items, stays within `max_size` bytes.
+///
+/// Items too large to fit in any chunk on their own are dropped with a warning.
+pub fn chunk_by_size<T: Serialize>(
+    items: Vec<T>,
+    base_size: usize,
+    max_size: usize,
+) -> Result<Vec<Vec<T>>> {
+    let mut chunks = vec![];
+    let mut chunk = vec![];
+    let mut size = base_size;
+    for item in items {
+        // Each item also needs a separating comma.
+        let item_size = serde_json::to_vec(&item)?.len() + 1;
+        if base_size + item_size > max_size {
+            warn!(
+                "Dropping item of {} bytes that does not fit in a {} byte payload.",
+                item_size, max_size
+            );
+            continue;
+        }
+        if size + item_size > max_size {
+            chunks.push(mem::take(&mut chunk));
+            size = base_size;
+        }
+        size += item_size;
+        chunk.push(item);
+    }
+    if !chunk.is_empty() || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_items_by_size() {
+        // "aaaa" is 6 bytes as JSON, 7 with the comma.
+        let items = vec!["aaaa"; 5];
+        let chunks = chunk_by_size(items, 10, 24).unwrap();
+        assert_eq!(
+            chunks.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+
+        let chunks = chunk_by_size(vec!["a".repeat(20), "b".to_string()], 10, 24).unwrap();
+        assert_eq!(chunks, vec![vec!["b".to_string()]]);
+
+        let chunks = chunk_by_size(Vec::<String>::new(), 10, 24).unwrap();
+        assert_eq!(chunks, vec![Vec::<String>::new()]);
+    }
+}
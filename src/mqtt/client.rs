@@ -0,0 +1,513 @@
+//! Shared MQTT client used by services and IPC.
+//!
+//! Publishes go through the spooler and are sent by the drain task; subscriptions register a
+//! callback with the router which is invoked for every incoming message matching the filter.
+//!
+//! The client owns one or more broker connections. Publishes use the first one, named after the
+//! thing; once it carries `maxSubscriptionsPerConnection` subscriptions, further connections
+//! named `<thing>#2`, `<thing>#3`, ... are opened for new subscriptions.
+//!
+//! Subscriptions are kept in the router and sent to the broker whenever a connection comes up, so
+//! subscribing while offline does not queue requests that nothing sends yet.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{bail, Error, Result};
+use bytes::Bytes;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{debug, info, trace, warn};
+
+use super::connection::{self, ConnectionState};
+use super::protocol::Client;
+use super::router::Router;
+use super::spool::{self, Spool};
+use super::with_timeout;
+use crate::config::Mqtt;
+
+pub type Callback = Arc<dyn Fn(&Message) + Send + Sync>;
+
+/// MQTT 5 message properties, ignored when connected with MQTT 3.1.1.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageProperties {
+    /// 1 if the payload is UTF-8 text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_format_indicator: Option<u8>,
+    /// Seconds after which the broker discards the message if it was not delivered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_expiry_interval: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_data: Option<Bytes>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub user_properties: Vec<(String, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl MessageProperties {
+    pub fn is_empty(&self) -> bool {
+        *self == MessageProperties::default()
+    }
+}
+
+/// A message received from the broker.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Bytes,
+    pub properties: MessageProperties,
+}
+
+#[derive(Debug, Clone)]
+pub struct PublishRequest {
+    pub topic: String,
+    pub qos: QoS,
+    /**
+     * Retain the message in the cloud MQTT broker (only last message with retain is actually kept).
+     * Subscribers will immediately receive the last retained message when they first subscribe.
+     */
+    pub retain: bool,
+    pub payload: Bytes,
+    pub properties: MessageProperties,
+}
+
+impl PublishRequest {
+    /// A non-retained QoS 1 publish, the default for nucleus messages.
+    pub fn new(topic: impl Into<String>, payload: impl Into<Bytes>) -> Self {
+        PublishRequest {
+            topic: topic.into(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload: payload.into(),
+            properties: MessageProperties::default(),
+        }
+    }
+
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    pub fn user_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties
+            .user_properties
+            .push((key.into(), value.into()));
+        self
+    }
+
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.properties.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn correlation_data(mut self, correlation_data: impl Into<Bytes>) -> Self {
+        self.properties.correlation_data = Some(correlation_data.into());
+        self
+    }
+
+    pub fn response_topic(mut self, response_topic: impl Into<String>) -> Self {
+        self.properties.response_topic = Some(response_topic.into());
+        self
+    }
+
+    pub fn message_expiry_interval(mut self, seconds: u32) -> Self {
+        self.properties.message_expiry_interval = Some(seconds);
+        self
+    }
+}
+
+/// Acknowledges that a publish request was accepted by the spooler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublishAck {
+    /// Spooler id of the message, in publish order.
+    pub id: u64,
+}
+
+#[derive(Clone)]
+pub struct SubscribeRequest {
+    pub topic: String,
+    pub qos: QoS,
+    pub callback: Callback,
+}
+
+#[derive(Clone)]
+pub struct UnsubscribeRequest {
+    pub topic: String,
+    pub callback: Callback,
+}
+
+/// The client of one broker connection, with the state of that connection.
+#[derive(Clone)]
+struct Connection {
+    client: Client,
+    state: watch::Receiver<ConnectionState>,
+}
+
+#[derive(Clone)]
+pub struct MqttClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    name: String,
+    /// Every open connection; the first one also carries the publishes.
+    connections: Mutex<Vec<Connection>>,
+    spool: Arc<Spool>,
+    state: watch::Receiver<ConnectionState>,
+    config: Mqtt,
+    router: Mutex<Router>,
+}
+
+impl MqttClient {
+    /// Wrap the connection `client` of thing `name` and start draining `spool` into it whenever
+    /// the connection is up.
+    pub fn new(
+        name: &str,
+        client: Client,
+        spool: Arc<Spool>,
+        state: watch::Receiver<ConnectionState>,
+        config: Mqtt,
+    ) -> Self {
+        tokio::spawn(spool::drain(
+            spool.clone(),
+            client.clone(),
+            state.clone(),
+            config.clone(),
+        ));
+        let inner = Arc::new(Inner {
+            name: name.to_string(),
+            connections: Mutex::new(vec![Connection {
+                client,
+                state: state.clone(),
+            }]),
+            spool,
+            state: state.clone(),
+            router: Mutex::new(Router::new(config.max_subscriptions_per_connection)),
+            config,
+        });
+        tokio::spawn(handle_connection_changes(Arc::downgrade(&inner), 0, state));
+        MqttClient { inner }
+    }
+
+    pub fn connected(&self) -> bool {
+        self.inner.state.borrow().is_connected()
+    }
+
+    pub fn config(&self) -> &Mqtt {
+        &self.inner.config
+    }
+
+    /// Watch the connection state, e.g. to pause work while the connection is interrupted.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.inner.state.clone()
+    }
+
+    /**
+     * Publish to a MQTT topic.
+     *
+     * The message is added to the spooler and published by the spooler drain task once connected.
+     * QoS 0 messages are rejected while offline unless `keepQos0WhenOffline` is set.
+     *
+     * @param request publish request
+     */
+    pub async fn publish(&self, request: PublishRequest) -> Result<PublishAck, Error> {
+        let max_size = self.inner.config.max_message_size_in_bytes;
+        if request.payload.len() > max_size {
+            bail!(
+                "Message size {} is larger than the maximum of {} bytes.",
+                request.payload.len(),
+                max_size
+            );
+        }
+        if !self.connected()
+            && request.qos == QoS::AtMostOnce
+            && !self.inner.spool.keep_qos0_when_offline()
+        {
+            bail!(
+                "Device is offline. Dropping QoS 0 message to {}.",
+                request.topic
+            );
+        }
+        trace!(
+            "Spooling message to {} (qos {:?}, retain {})",
+            request.topic,
+            request.qos,
+            request.retain
+        );
+        let id = self.inner.spool.add_message(request)?;
+        Ok(PublishAck { id })
+    }
+
+    /// Subscribe to a topic filter; `callback` is invoked for every message matching it.
+    ///
+    /// The broker is only sent a subscription the first time a filter is used or when a higher
+    /// QoS is requested for it, and only once the connection is up.
+    pub async fn subscribe(&self, request: SubscribeRequest) -> Result<()> {
+        let (subscription, connection) = {
+            let mut router = self.inner.router.lock().unwrap();
+            let (topic, callback) = (request.topic.clone(), request.callback.clone());
+            let subscription = match router.add(request) {
+                Some(subscription) => subscription,
+                None => return Ok(()),
+            };
+            match self.connection(subscription.connection) {
+                Ok(connection) => (subscription, connection),
+                Err(e) => {
+                    router.remove(&topic, &callback);
+                    return Err(e);
+                }
+            }
+        };
+        // Sent when the connection comes up otherwise.
+        if !connection.state.borrow().is_connected() {
+            debug!(
+                "Subscribing to {} once connection {} is up",
+                subscription.topic,
+                subscription.connection + 1
+            );
+            return Ok(());
+        }
+        debug!(
+            "Subscribing to {} on connection {}",
+            subscription.topic,
+            subscription.connection + 1
+        );
+        let timeout = self.inner.config.operation_timeout();
+        with_timeout(
+            timeout,
+            connection
+                .client
+                .subscribe(subscription.topic, subscription.qos),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Remove a callback; the filter is unsubscribed from the broker once no callback is left.
+    pub async fn unsubscribe(&self, request: UnsubscribeRequest) -> Result<()> {
+        let connection = self
+            .inner
+            .router
+            .lock()
+            .unwrap()
+            .remove(&request.topic, &request.callback);
+        if let Some(connection) = connection {
+            let connection = self.inner.connections.lock().unwrap()[connection].clone();
+            if !connection.state.borrow().is_connected() {
+                return Ok(());
+            }
+            debug!("Unsubscribing from {}", request.topic);
+            let timeout = self.inner.config.operation_timeout();
+            with_timeout(timeout, connection.client.unsubscribe(&request.topic)).await?;
+        }
+        Ok(())
+    }
+
+    /// The client of connection `index`, opening a new connection if `index` is the next one.
+    fn connection(&self, index: usize) -> Result<Connection> {
+        let mut connections = self.inner.connections.lock().unwrap();
+        if let Some(connection) = connections.get(index) {
+            return Ok(connection.clone());
+        }
+        let client_id = format!("{}#{}", self.inner.name, index + 1);
+        info!("Opening MQTT connection {}", client_id);
+        let (client, eventloop) = super::init(&client_id)?;
+        let (state_tx, state) = watch::channel(ConnectionState::Disconnected);
+        tokio::spawn(connection::run(eventloop, state_tx, self.clone()));
+        tokio::spawn(handle_connection_changes(
+            Arc::downgrade(&self.inner),
+            index,
+            state.clone(),
+        ));
+        let connection = Connection { client, state };
+        connections.push(connection.clone());
+        Ok(connection)
+    }
+
+    /// Hand an incoming message to every callback whose filter matches its topic.
+    pub fn dispatch(&self, message: &Message) {
+        let callbacks = self.inner.router.lock().unwrap().callbacks(message);
+        if callbacks.is_empty() {
+            debug!("No subscription for message on {}", message.topic);
+        }
+        for callback in callbacks {
+            callback(message);
+        }
+    }
+}
+
+/// Drop QoS 0 messages when the publishing connection is interrupted and send the broker
+/// subscriptions of connection `index` whenever it comes up.
+async fn handle_connection_changes(
+    inner: Weak<Inner>,
+    index: usize,
+    mut state: watch::Receiver<ConnectionState>,
+) {
+    while state.changed().await.is_ok() {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let current = *state.borrow();
+        match current {
+            ConnectionState::Interrupted if index == 0 => {
+                inner.spool.pop_out_messages_with_qos_zero()
+            }
+            ConnectionState::Connected | ConnectionState::Resumed => {
+                let subscriptions = inner.router.lock().unwrap().broker_subscriptions(index);
+                if !subscriptions.is_empty() {
+                    info!("Subscribing to {} topics", subscriptions.len());
+                    let client = inner.connections.lock().unwrap()[index].client.clone();
+                    let timeout = inner.config.operation_timeout();
+                    if let Err(e) =
+                        with_timeout(timeout, client.subscribe_many(subscriptions)).await
+                    {
+                        warn!("Failed to subscribe: {}", e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::config::Spooler;
+
+    fn client(
+        config: Mqtt,
+    ) -> (
+        MqttClient,
+        rumqttc::EventLoop,
+        watch::Sender<ConnectionState>,
+    ) {
+        let (client, eventloop) = rumqttc::AsyncClient::new(
+            rumqttc::MqttOptions::new("client-test", "localhost", 1883),
+            10,
+        );
+        let spool = Arc::new(Spool::new(&Spooler::default(), Path::new(".")).unwrap());
+        let (state_tx, state) = watch::channel(ConnectionState::Disconnected);
+        let client = MqttClient::new("client-test", Client::V3(client), spool, state, config);
+        (client, eventloop, state_tx)
+    }
+
+    #[tokio::test]
+    async fn spools_publishes_while_offline() {
+        let config = Mqtt {
+            max_message_size_in_bytes: 8,
+            ..Mqtt::default()
+        };
+        let (client, _eventloop, _state) = client(config);
+        assert!(!client.connected());
+
+        let first = client.publish(PublishRequest::new("a", "1")).await.unwrap();
+        let second = client
+            .publish(PublishRequest::new("b", "2").retain(true))
+            .await
+            .unwrap();
+        assert!(first.id < second.id);
+        assert_eq!(client.inner.spool.len(), 2);
+
+        let qos0 = PublishRequest::new("c", "3").qos(QoS::AtMostOnce);
+        assert!(client.publish(qos0).await.is_err());
+        let oversized = PublishRequest::new("d", "123456789");
+        assert!(client.publish(oversized).await.is_err());
+        assert_eq!(client.inner.spool.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn dispatches_to_matching_callbacks() {
+        let (client, _eventloop, _state) = client(Mqtt::default());
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let callback: Callback = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        client
+            .subscribe(SubscribeRequest {
+                topic: "things/+/shadow".to_string(),
+                qos: QoS::AtLeastOnce,
+                callback: callback.clone(),
+            })
+            .await
+            .unwrap();
+
+        let message = |topic: &str| Message {
+            topic: topic.to_string(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload: Bytes::from_static(b"{}"),
+            properties: MessageProperties::default(),
+        };
+        client.dispatch(&message("things/a/shadow"));
+        client.dispatch(&message("things/a/jobs"));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        client
+            .unsubscribe(UnsubscribeRequest {
+                topic: "things/+/shadow".to_string(),
+                callback,
+            })
+            .await
+            .unwrap();
+        client.dispatch(&message("things/b/shadow"));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn subscribes_once_connected() {
+        let config = Mqtt {
+            operation_timeout_ms: 100,
+            ..Mqtt::default()
+        };
+        let (client, _eventloop, state) = client(config);
+        let callback: Callback = Arc::new(|_| {});
+        // More subscriptions than the request channel holds, with no event loop polling it.
+        for topic in 0..20 {
+            client
+                .subscribe(SubscribeRequest {
+                    topic: format!("topic/{topic}"),
+                    qos: QoS::AtLeastOnce,
+                    callback: callback.clone(),
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            client
+                .inner
+                .router
+                .lock()
+                .unwrap()
+                .broker_subscriptions(0)
+                .len(),
+            20
+        );
+
+        state.send_replace(ConnectionState::Connected);
+        tokio::task::yield_now().await;
+        client
+            .subscribe(SubscribeRequest {
+                topic: "topic/connected".to_string(),
+                qos: QoS::AtLeastOnce,
+                callback,
+            })
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! MQTT connection event loop.
+//!
+//! Polls the `rumqttc` event loop, hands incoming messages to the client, and publishes the
+//! connection state on a watch channel so services can react to interruptions. After a
+//! connection error the next attempt is delayed with exponential backoff and full jitter, so a
+//! fleet of devices does not reconnect in lockstep after an outage. A ping that is not answered
+//! within `pingTimeoutMs` drops the connection so it can be re-established.
+
+use rand::Rng;
+use rumqttc::v5;
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::{debug, info, warn};
+
+use super::protocol::{ConnectionError, Event, EventLoop};
+use super::MqttClient;
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Not connected yet since the nucleus started.
+    Disconnected,
+    /// The first connection is up.
+    Connected,
+    /// A connection that was up has dropped; the nucleus is trying to reconnect.
+    Interrupted,
+    /// The connection is up again after an interruption.
+    Resumed,
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        matches!(self, ConnectionState::Connected | ConnectionState::Resumed)
+    }
+}
+
+/// Exponential backoff with full jitter.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt, a random value up to `min * 2^attempt` capped at `max`.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .min
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        rand::thread_rng().gen_range(Duration::ZERO..=ceiling)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Drive the MQTT connection forever, reconnecting with backoff when it drops.
+pub async fn run(
+    mut eventloop: EventLoop,
+    state: watch::Sender<ConnectionState>,
+    client: MqttClient,
+) {
+    let mut backoff = Backoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+    let ping_timeout = client.config().ping_timeout();
+    let mut ping_deadline: Option<Instant> = None;
+    loop {
+        let event = match ping_deadline {
+            Some(deadline) => tokio::select! {
+                event = eventloop.poll() => event,
+                _ = sleep_until(deadline) => {
+                    ping_deadline = None;
+                    warn!("No ping response within {:?}, dropping MQTT connection.", ping_timeout);
+                    eventloop.clean();
+                    if state.borrow().is_connected() {
+                        state.send_replace(ConnectionState::Interrupted);
+                    }
+                    continue;
+                }
+            },
+            None => eventloop.poll().await,
+        };
+        match event {
+            Ok(Event::PingReq) => {
+                ping_deadline = Some(Instant::now() + ping_timeout);
+            }
+            Ok(Event::PingResp) => ping_deadline = None,
+            Ok(Event::ConnAck) => {
+                backoff.reset();
+                let next = match *state.borrow() {
+                    ConnectionState::Disconnected => ConnectionState::Connected,
+                    _ => ConnectionState::Resumed,
+                };
+                info!("MQTT connection {:?}.", next);
+                state.send_replace(next);
+            }
+            Ok(Event::Publish(message)) => client.dispatch(&message),
+            Ok(Event::Other(event)) => debug!("{}", event),
+            Err(e) => {
+                ping_deadline = None;
+                if state.borrow().is_connected() {
+                    warn!("MQTT connection interrupted: {}", disconnect_reason(&e));
+                    state.send_replace(ConnectionState::Interrupted);
+                } else {
+                    warn!("MQTT connection failed: {}", disconnect_reason(&e));
+                }
+                let delay = backoff.next_delay();
+                info!("Retrying MQTT connection in {:?}", delay);
+                sleep(delay).await;
+            }
+        }
+    }
+}
+
+fn disconnect_reason(e: &ConnectionError) -> String {
+    match e {
+        ConnectionError::V3(rumqttc::ConnectionError::ConnectionRefused(code)) => {
+            format!("connection refused by broker ({code:?})")
+        }
+        ConnectionError::V5(v5::ConnectionError::ConnectionRefused(code)) => {
+            format!("connection refused by broker ({code:?})")
+        }
+        ConnectionError::V3(rumqttc::ConnectionError::Io(e))
+        | ConnectionError::V5(v5::ConnectionError::Io(e)) => {
+            format!("I/O error ({}): {}", e.kind(), e)
+        }
+        ConnectionError::V3(rumqttc::ConnectionError::NetworkTimeout)
+        | ConnectionError::V5(v5::ConnectionError::Timeout(_)) => {
+            "connection timed out".to_string()
+        }
+        ConnectionError::V3(rumqttc::ConnectionError::MqttState(e)) => {
+            format!("protocol error: {e}")
+        }
+        ConnectionError::V5(v5::ConnectionError::MqttState(e)) => format!("protocol error: {e}"),
+        ConnectionError::V3(e) => e.to_string(),
+        ConnectionError::V5(e) => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        for attempt in 0..10 {
+            let ceiling = Duration::from_secs(1 << attempt.min(3));
+            assert!(backoff.next_delay() <= ceiling);
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
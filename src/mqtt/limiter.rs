@@ -0,0 +1,98 @@
+//! Token bucket rate limiter for MQTT publishes.
+//!
+//! AWS IoT Core throttles each connection to a number of publishes and a number of bytes per
+//! second. The spooler drain task takes tokens from one limiter for each message and from another
+//! for each payload byte before handing the message to the client, so that we wait locally instead
+//! of being throttled by the broker.
+//!
+//! Up to one second worth of unused tokens is stored for bursts. A request for more tokens than
+//! are stored is allowed through and the deficit is paid back by the next caller.
+//!
+//! A rate that is not positive, such as a zero in the config, means no limit.
+
+use std::sync::Mutex;
+
+use tokio::time::{sleep_until, Duration, Instant};
+
+pub struct RateLimiter {
+    rate: f64,
+    max_stored: f64,
+    state: Mutex<State>,
+}
+
+struct State {
+    stored: f64,
+    next_free: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` tokens per second, or any number if `rate` is not positive.
+    pub fn new(rate: f64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            max_stored: rate,
+            state: Mutex::new(State {
+                stored: 0.0,
+                next_free: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `permits` tokens are available and take them.
+    pub async fn acquire(&self, permits: usize) {
+        sleep_until(self.reserve(permits, Instant::now())).await;
+    }
+
+    /// Take `permits` tokens and return the instant the caller may proceed.
+    fn reserve(&self, permits: usize, now: Instant) -> Instant {
+        if self.rate.is_nan() || self.rate <= 0.0 {
+            return now;
+        }
+        let mut state = self.state.lock().unwrap();
+        if now > state.next_free {
+            let refill = (now - state.next_free).as_secs_f64() * self.rate;
+            state.stored = self.max_stored.min(state.stored + refill);
+            state.next_free = now;
+        }
+        let ready_at = state.next_free;
+        let permits = permits as f64;
+        let from_stored = permits.min(state.stored);
+        state.stored -= from_stored;
+        state.next_free += Duration::from_secs_f64((permits - from_stored) / self.rate);
+        ready_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_tokens_beyond_the_rate() {
+        let limiter = RateLimiter::new(10.0);
+        let start = limiter.state.lock().unwrap().next_free;
+        assert_eq!(limiter.reserve(1, start), start);
+        let second = limiter.reserve(10, start);
+        assert_eq!(second - start, Duration::from_millis(100));
+        assert_eq!(
+            limiter.reserve(1, start) - start,
+            Duration::from_millis(1100)
+        );
+
+        // After a long idle period at most one second of tokens is stored.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(10, later), later);
+        assert_eq!(limiter.reserve(10, later), later);
+        assert_eq!(limiter.reserve(1, later) - later, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn does_not_limit_without_a_positive_rate() {
+        for rate in [0.0, -1.0, f64::NAN] {
+            let limiter = RateLimiter::new(rate);
+            let now = Instant::now();
+            assert_eq!(limiter.reserve(1, now), now);
+            assert_eq!(limiter.reserve(1_000_000, now), now);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::config::{self, MqttVersion};
+use crate::proxy::ProxySettings;
+use anyhow::{bail, Error, Ok, Result};
+use rumqttc::v5::{self, mqttbytes::v5::ConnectProperties};
+use rumqttc::{self, AsyncClient, MqttOptions, Transport};
+use tracing::{info, warn};
+
+use std::{fs, future::Future, path::Path, time::Duration};
+
+pub mod chunked;
+pub mod client;
+pub mod connection;
+pub mod limiter;
+pub mod protocol;
+pub mod router;
+pub mod spool;
+pub mod tls;
+pub mod websocket;
+
+pub use client::{
+    Callback, Message, MessageProperties, MqttClient, PublishAck, PublishRequest, SubscribeRequest,
+    UnsubscribeRequest,
+};
+pub use connection::ConnectionState;
+pub use protocol::{Client, EventLoop};
+
+/// ALPN protocol that lets AWS IoT Core accept certificate-authenticated MQTT on port 443.
+const ALPN_MQTT_CA: &[u8] = b"x-amzn-mqtt-ca";
+/// Room for the fixed header, topic and properties on top of the largest payload.
+const PACKET_OVERHEAD_BYTES: usize = 65_536;
+/// Requests queued between the clients and the event loop.
+const REQUEST_CHANNEL_CAPACITY: usize = 10;
+
+/// Run a client operation, failing if it is not accepted within `timeout`.
+pub(crate) async fn with_timeout<T>(
+    timeout: Duration,
+    operation: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match tokio::time::timeout(timeout, operation).await {
+        Result::Ok(result) => Ok(result?),
+        Err(_) => bail!("MQTT operation timed out after {:?}.", timeout),
+    }
+}
+
+/// The keep-alive interval of `mqtt`, which must leave room for the ping timeout.
+fn check_keep_alive(mqtt: &config::Mqtt) -> Result<Duration> {
+    let keep_alive = mqtt.keep_alive();
+    if !keep_alive.is_zero() && keep_alive < Duration::from_secs(1) {
+        bail!("keepAliveTimeoutMs must be 0 or at least 1000.");
+    }
+    if !keep_alive.is_zero() && mqtt.ping_timeout() >= keep_alive {
+        bail!("pingTimeoutMs must be less than keepAliveTimeoutMs.");
+    }
+    Ok(keep_alive)
+}
+
+pub fn init(name: &str) -> Result<(Client, EventLoop), Error> {
+    let configuration = &config::Config::global().services.kernel.configuration;
+    let endpoint = configuration.iot_data_endpoint.as_str();
+    let mqtt = &configuration.mqtt;
+    // info!("Endpoint: {}", endpoint);
+
+    let root_dir = Path::new(".");
+    let ca_file_path = root_dir.join("rootCA.pem");
+    let priv_key_file_path = root_dir.join("privKey.key");
+    let cert_file_path = root_dir.join("thingCert.crt");
+    // info!("{:?}", endpoint);
+
+    let keep_alive = check_keep_alive(mqtt)?;
+
+    let (broker, port, transport) = if mqtt.use_web_socket {
+        let transport =
+            Transport::wss_with_config(tls::server_auth_configuration(&fs::read(ca_file_path)?)?);
+        (websocket::broker_url(endpoint), websocket::PORT, transport)
+    } else {
+        let alpn = (mqtt.port == 443).then(|| vec![ALPN_MQTT_CA.to_vec()]);
+        let transport = Transport::tls_with_config(tls::tls_configuration(
+            &fs::read(ca_file_path)?,
+            &fs::read(cert_file_path)?,
+            &fs::read(priv_key_file_path)?,
+            alpn,
+        )?);
+        (endpoint.to_string(), mqtt.port, transport)
+    };
+    let proxy = ProxySettings::global()?.filter(|proxy| !proxy.bypass(endpoint));
+    let max_packet_size = mqtt.max_message_size_in_bytes + PACKET_OVERHEAD_BYTES;
+
+    // Settings shared by the MQTT 3.1.1 and MQTT 5 options.
+    macro_rules! configure {
+        ($options:expr) => {{
+            $options.set_transport(transport).set_keep_alive(keep_alive);
+            if mqtt.use_web_socket {
+                let region = configuration.region.clone();
+                $options.set_request_modifier(move |mut request| {
+                    let region = region.clone();
+                    async move {
+                        let url = request.uri().to_string();
+                        match websocket::presign(&url, &region).await {
+                            Result::Ok(signed) => match signed.parse() {
+                                Result::Ok(uri) => *request.uri_mut() = uri,
+                                Err(e) => warn!("Invalid presigned MQTT url: {}", e),
+                            },
+                            Err(e) => warn!("Failed to sign MQTT WebSocket request: {:#}", e),
+                        }
+                        request
+                    }
+                });
+            }
+            if let Some(proxy) = &proxy {
+                info!("Connecting to MQTT through proxy {}", proxy.url());
+                $options.set_proxy(proxy.mqtt()?);
+            }
+        }};
+    }
+
+    match mqtt.version {
+        MqttVersion::Mqtt3 => {
+            let mut options = MqttOptions::new(name, broker, port);
+            configure!(options);
+            options
+                .set_inflight(mqtt.max_in_flight_publishes)
+                .set_max_packet_size(max_packet_size, max_packet_size);
+            let (client, eventloop) = AsyncClient::new(options, REQUEST_CHANNEL_CAPACITY);
+            Ok((Client::V3(client), EventLoop::V3(Box::new(eventloop))))
+        }
+        MqttVersion::Mqtt5 => {
+            let mut options = v5::MqttOptions::new(name, broker, port);
+            configure!(options);
+            let session_expiry = mqtt.session_expiry_interval_seconds;
+            let mut properties = ConnectProperties::new();
+            properties.session_expiry_interval = (session_expiry > 0).then_some(session_expiry);
+            options
+                .set_connect_properties(properties)
+                .set_clean_start(session_expiry == 0)
+                .set_outgoing_inflight_upper_limit(mqtt.max_in_flight_publishes)
+                .set_max_packet_size(Some(u32::try_from(max_packet_size)?));
+            let (client, eventloop) = v5::AsyncClient::new(options, REQUEST_CHANNEL_CAPACITY);
+            Ok((Client::V5(client), EventLoop::V5(Box::new(eventloop))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_keep_alive() {
+        let mqtt = |keep_alive_timeout_ms, ping_timeout_ms| config::Mqtt {
+            keep_alive_timeout_ms,
+            ping_timeout_ms,
+            ..config::Mqtt::default()
+        };
+        assert_eq!(
+            check_keep_alive(&config::Mqtt::default()).unwrap(),
+            Duration::from_secs(60)
+        );
+        assert!(check_keep_alive(&mqtt(0, 30_000)).unwrap().is_zero());
+        assert!(check_keep_alive(&mqtt(500, 100)).is_err());
+        assert!(check_keep_alive(&mqtt(10_000, 10_000)).is_err());
+    }
+
+    #[tokio::test]
+    async fn times_out_operations() {
+        let timeout = Duration::from_millis(10);
+        let accepted = async { Ok(42) };
+        assert_eq!(with_timeout(timeout, accepted).await.unwrap(), 42);
+        let stuck = std::future::pending::<Result<()>>();
+        assert!(with_timeout(timeout, stuck).await.is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! MQTT protocol versions.
+//!
+//! `rumqttc` has separate clients for MQTT 3.1.1 and MQTT 5. The nucleus talks to both through
+//! the [`Client`] and [`EventLoop`] wrappers, which convert messages to and from the version
+//! independent [`PublishRequest`] and [`Message`]. MQTT 5 properties are dropped on 3.1.1
+//! connections.
+
+use anyhow::Result;
+use rumqttc::v5;
+use rumqttc::v5::mqttbytes::v5::{Filter, PublishProperties};
+use rumqttc::{Outgoing, Packet, QoS, SubscribeFilter};
+
+use super::client::{Message, MessageProperties, PublishRequest};
+
+#[derive(Clone)]
+pub enum Client {
+    V3(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl Client {
+    pub async fn publish(&self, request: PublishRequest) -> Result<()> {
+        match self {
+            Client::V3(client) => {
+                client
+                    .publish_bytes(request.topic, request.qos, request.retain, request.payload)
+                    .await?
+            }
+            Client::V5(client) => {
+                client
+                    .publish_bytes_with_properties(
+                        request.topic,
+                        v5_qos(request.qos),
+                        request.retain,
+                        request.payload,
+                        request.properties.into(),
+                    )
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn subscribe(&self, topic: String, qos: QoS) -> Result<()> {
+        match self {
+            Client::V3(client) => client.subscribe(topic, qos).await?,
+            Client::V5(client) => client.subscribe(topic, v5_qos(qos)).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn subscribe_many(&self, subscriptions: Vec<(String, QoS)>) -> Result<()> {
+        match self {
+            Client::V3(client) => {
+                let filters = subscriptions
+                    .into_iter()
+                    .map(|(topic, qos)| SubscribeFilter::new(topic, qos));
+                client.subscribe_many(filters).await?
+            }
+            Client::V5(client) => {
+                let filters = subscriptions
+                    .into_iter()
+                    .map(|(topic, qos)| Filter::new(topic, v5_qos(qos)));
+                client.subscribe_many(filters).await?
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        match self {
+            Client::V3(client) => client.unsubscribe(topic).await?,
+            Client::V5(client) => client.unsubscribe(topic).await?,
+        }
+        Ok(())
+    }
+}
+
+/// Connection events the nucleus reacts to.
+#[derive(Debug)]
+pub enum Event {
+    ConnAck,
+    Publish(Message),
+    PingReq,
+    PingResp,
+    Other(String),
+}
+
+pub enum ConnectionError {
+    V3(rumqttc::ConnectionError),
+    V5(v5::ConnectionError),
+}
+
+pub enum EventLoop {
+    V3(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+impl EventLoop {
+    pub async fn poll(&mut self) -> Result<Event, ConnectionError> {
+        match self {
+            EventLoop::V3(eventloop) => {
+                let event = eventloop.poll().await.map_err(ConnectionError::V3)?;
+                Ok(match event {
+                    rumqttc::Event::Incoming(Packet::ConnAck(_)) => Event::ConnAck,
+                    rumqttc::Event::Incoming(Packet::Publish(publish)) => Event::Publish(Message {
+                        topic: publish.topic,
+                        qos: publish.qos,
+                        retain: publish.retain,
+                        payload: publish.payload,
+                        properties: MessageProperties::default(),
+                    }),
+                    rumqttc::Event::Incoming(Packet::PingResp) => Event::PingResp,
+                    rumqttc::Event::Outgoing(Outgoing::PingReq) => Event::PingReq,
+                    event => Event::Other(format!("{event:?}")),
+                })
+            }
+            EventLoop::V5(eventloop) => {
+                let event = eventloop.poll().await.map_err(ConnectionError::V5)?;
+                Ok(match event {
+                    v5::Event::Incoming(v5::Incoming::ConnAck(_)) => Event::ConnAck,
+                    v5::Event::Incoming(v5::Incoming::Publish(publish)) => {
+                        Event::Publish(Message {
+                            topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                            qos: v3_qos(publish.qos),
+                            retain: publish.retain,
+                            payload: publish.payload,
+                            properties: publish.properties.map(Into::into).unwrap_or_default(),
+                        })
+                    }
+                    v5::Event::Incoming(v5::Incoming::PingResp(_)) => Event::PingResp,
+                    v5::Event::Outgoing(Outgoing::PingReq) => Event::PingReq,
+                    event => Event::Other(format!("{event:?}")),
+                })
+            }
+        }
+    }
+
+    /// Drop the current network connection; the next poll reconnects.
+    pub fn clean(&mut self) {
+        match self {
+            EventLoop::V3(eventloop) => eventloop.clean(),
+            EventLoop::V5(eventloop) => eventloop.clean(),
+        }
+    }
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn v3_qos(qos: v5::mqttbytes::QoS) -> QoS {
+    match qos {
+        v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+        v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+        v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+impl From<MessageProperties> for PublishProperties {
+    fn from(properties: MessageProperties) -> Self {
+        PublishProperties {
+            payload_format_indicator: properties.payload_format_indicator,
+            message_expiry_interval: properties.message_expiry_interval,
+            topic_alias: None,
+            response_topic: properties.response_topic,
+            correlation_data: properties.correlation_data,
+            user_properties: properties.user_properties,
+            subscription_identifiers: vec![],
+            content_type: properties.content_type,
+        }
+    }
+}
+
+impl From<PublishProperties> for MessageProperties {
+    fn from(properties: PublishProperties) -> Self {
+        MessageProperties {
+            payload_format_indicator: properties.payload_format_indicator,
+            message_expiry_interval: properties.message_expiry_interval,
+            response_topic: properties.response_topic,
+            correlation_data: properties.correlation_data,
+            user_properties: properties.user_properties,
+            content_type: properties.content_type,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn converts_properties() {
+        let properties = MessageProperties {
+            content_type: Some("application/json".to_string()),
+            correlation_data: Some(Bytes::from_static(b"42")),
+            response_topic: Some("reply/to".to_string()),
+            message_expiry_interval: Some(60),
+            user_properties: vec![("component".to_string(), "a".to_string())],
+            ..MessageProperties::default()
+        };
+        let wire: PublishProperties = properties.clone().into();
+        assert_eq!(wire.message_expiry_interval, Some(60));
+        assert_eq!(MessageProperties::from(wire), properties);
+    }
+}
@@ -0,0 +1,225 @@
+//! Subscription multiplexer.
+//!
+//! Many handlers can subscribe to the same or overlapping topic filters. The router keeps one
+//! broker subscription per distinct filter (at the highest QoS any handler asked for), dispatches
+//! every incoming message to each handler whose filter matches, and remembers the broker
+//! subscriptions so they can be restored after a reconnect.
+//!
+//! AWS IoT Core limits the subscriptions of a single connection, so each broker subscription is
+//! assigned to a connection with room left, and a new connection is requested once all existing
+//! ones are full.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rumqttc::QoS;
+
+use super::client::{Callback, Message, SubscribeRequest};
+
+/// A subscription to send to the broker on the connection at index `connection`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerSubscription {
+    pub topic: String,
+    pub qos: QoS,
+    pub connection: usize,
+}
+
+pub struct Router {
+    subscriptions: Vec<SubscribeRequest>,
+    /// Broker subscriptions by filter, with the QoS and the connection they were made on.
+    broker: HashMap<String, (QoS, usize)>,
+    max_subscriptions_per_connection: usize,
+}
+
+impl Router {
+    pub fn new(max_subscriptions_per_connection: usize) -> Self {
+        Router {
+            subscriptions: vec![],
+            broker: HashMap::new(),
+            max_subscriptions_per_connection: max_subscriptions_per_connection.max(1),
+        }
+    }
+
+    /// Register a handler, returning the subscription to send to the broker if one is needed.
+    ///
+    /// The returned connection may not exist yet, in which case the caller opens it.
+    pub fn add(&mut self, request: SubscribeRequest) -> Option<BrokerSubscription> {
+        let connection = match self.broker.get(&request.topic) {
+            Some((qos, connection)) if (*qos as u8) < (request.qos as u8) => *connection,
+            Some(_) => {
+                self.subscriptions.push(request);
+                return None;
+            }
+            None => self.free_connection(),
+        };
+        let subscription = BrokerSubscription {
+            topic: request.topic.clone(),
+            qos: request.qos,
+            connection,
+        };
+        self.broker
+            .insert(subscription.topic.clone(), (subscription.qos, connection));
+        self.subscriptions.push(request);
+        Some(subscription)
+    }
+
+    /// Remove a handler, returning the connection to unsubscribe on if the broker subscription
+    /// is no longer needed.
+    pub fn remove(&mut self, topic: &str, callback: &Callback) -> Option<usize> {
+        self.subscriptions
+            .retain(|s| s.topic != topic || !Arc::ptr_eq(&s.callback, callback));
+        if self.subscriptions.iter().any(|s| s.topic == topic) {
+            return None;
+        }
+        self.broker.remove(topic).map(|(_, connection)| connection)
+    }
+
+    /// Handlers whose filter matches the topic of `message`, each handler at most once.
+    pub fn callbacks(&self, message: &Message) -> Vec<Callback> {
+        let mut callbacks: Vec<Callback> = vec![];
+        for subscription in &self.subscriptions {
+            if topic_matches(&subscription.topic, &message.topic)
+                && !callbacks
+                    .iter()
+                    .any(|c| Arc::ptr_eq(c, &subscription.callback))
+            {
+                callbacks.push(subscription.callback.clone());
+            }
+        }
+        callbacks
+    }
+
+    /// Every distinct filter currently subscribed on the broker through `connection`.
+    pub fn broker_subscriptions(&self, connection: usize) -> Vec<(String, QoS)> {
+        self.broker
+            .iter()
+            .filter(|(_, (_, c))| *c == connection)
+            .map(|(topic, (qos, _))| (topic.clone(), *qos))
+            .collect()
+    }
+
+    /// The first connection with room for another subscription.
+    fn free_connection(&self) -> usize {
+        let mut counts: Vec<usize> = vec![];
+        for (_, connection) in self.broker.values() {
+            if counts.len() <= *connection {
+                counts.resize(connection + 1, 0);
+            }
+            counts[*connection] += 1;
+        }
+        counts
+            .iter()
+            .position(|count| *count < self.max_subscriptions_per_connection)
+            .unwrap_or(counts.len())
+    }
+}
+
+/// Whether `topic` matches the MQTT topic `filter`, which may contain `+` and `#` wildcards.
+///
+/// Topics starting with `$` (such as `$aws/...`) are not matched by a wildcard in the first level.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(topic: &str, qos: QoS, callback: &Callback) -> SubscribeRequest {
+        SubscribeRequest {
+            topic: topic.to_string(),
+            qos,
+            callback: callback.clone(),
+        }
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(topic_matches("a/b/c", "a/b/c"));
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("+/+", "a/b"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+        assert!(!topic_matches("#", "$aws/things/t/shadow/update"));
+        assert!(topic_matches(
+            "$aws/things/+/shadow/#",
+            "$aws/things/t/shadow/update"
+        ));
+    }
+
+    #[test]
+    fn deduplicates_broker_subscriptions() {
+        let first: Callback = Arc::new(|_| {});
+        let second: Callback = Arc::new(|_| {});
+        let mut router = Router::new(50);
+        assert!(router
+            .add(request("a/+", QoS::AtMostOnce, &first))
+            .is_some());
+        assert!(router
+            .add(request("a/+", QoS::AtMostOnce, &second))
+            .is_none());
+        assert_eq!(
+            router.add(request("a/+", QoS::AtLeastOnce, &second)),
+            Some(BrokerSubscription {
+                topic: "a/+".to_string(),
+                qos: QoS::AtLeastOnce,
+                connection: 0,
+            })
+        );
+        assert!(router
+            .add(request("a/#", QoS::AtMostOnce, &first))
+            .is_some());
+
+        let message = Message {
+            topic: "a/b".to_string(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            payload: Default::default(),
+            properties: Default::default(),
+        };
+        assert_eq!(router.callbacks(&message).len(), 2);
+
+        assert_eq!(router.remove("a/+", &first), None);
+        assert_eq!(router.remove("a/+", &second), Some(0));
+        assert_eq!(router.broker_subscriptions(0).len(), 1);
+        assert_eq!(router.callbacks(&message).len(), 1);
+    }
+
+    #[test]
+    fn spreads_subscriptions_over_connections() {
+        let callback: Callback = Arc::new(|_| {});
+        let mut router = Router::new(2);
+        let connections: Vec<usize> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|topic| {
+                router
+                    .add(request(topic, QoS::AtLeastOnce, &callback))
+                    .unwrap()
+                    .connection
+            })
+            .collect();
+        assert_eq!(connections, vec![0, 0, 1, 1, 2]);
+
+        // A freed slot is reused before opening another connection.
+        assert_eq!(router.remove("b", &callback), Some(0));
+        let subscription = router.add(request("f", QoS::AtLeastOnce, &callback));
+        assert_eq!(subscription.unwrap().connection, 0);
+        assert_eq!(router.broker_subscriptions(1).len(), 2);
+    }
+}
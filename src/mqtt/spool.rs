@@ -0,0 +1,377 @@
+//! # MQTT publish spooler
+//!
+//! Every publish request goes through the spooler first. A single drain task takes messages out
+//! of the spooler in order and hands them to the MQTT client once the connection is up, so that
+//! messages published while the device is offline are kept instead of lost.
+//!
+//! The spooler is bounded by `maxSizeInBytes`. When a new message does not fit, QoS 0 messages
+//! are evicted oldest first; if that still does not make enough room the new message is rejected.
+//!
+//! # Storage
+//! - `Memory`: messages are lost when the nucleus restarts.
+//! - `Disk`: every message is also written to `<root>/spool/<id>.json` and reloaded on startup.
+//!
+//! # Sample Configuration
+//! ```text
+//! services:
+//!   aws.greengrass.Nucleus:
+//!     configuration:
+//!       mqtt:
+//!         spooler:
+//!           storageType: "Disk"
+//!           maxSizeInBytes: 2621440
+//!           keepQos0WhenOffline: false
+//! ```
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Error, Result};
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Notify};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, warn};
+
+use super::client::{MessageProperties, PublishRequest};
+use super::connection::ConnectionState;
+use super::limiter::RateLimiter;
+use super::protocol::Client;
+use super::with_timeout;
+use crate::config::{Mqtt, Spooler, SpoolerStorageType};
+
+const SPOOL_DIR: &str = "spool";
+const PUBLISH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// On-disk representation of a spooled message.
+#[derive(Serialize, Deserialize, Debug)]
+struct SpooledMessage {
+    topic: String,
+    qos: u8,
+    retain: bool,
+    payload: Vec<u8>,
+    #[serde(default, skip_serializing_if = "MessageProperties::is_empty")]
+    properties: MessageProperties,
+}
+
+impl From<&PublishRequest> for SpooledMessage {
+    fn from(request: &PublishRequest) -> Self {
+        SpooledMessage {
+            topic: request.topic.clone(),
+            qos: request.qos as u8,
+            retain: request.retain,
+            payload: request.payload.to_vec(),
+            properties: request.properties.clone(),
+        }
+    }
+}
+
+impl TryFrom<SpooledMessage> for PublishRequest {
+    type Error = Error;
+
+    fn try_from(message: SpooledMessage) -> Result<Self> {
+        let mut request = PublishRequest::new(message.topic, message.payload)
+            .qos(rumqttc::qos(message.qos)?)
+            .retain(message.retain);
+        request.properties = message.properties;
+        Ok(request)
+    }
+}
+
+struct Queue {
+    messages: VecDeque<(u64, PublishRequest)>,
+    size: usize,
+    next_id: u64,
+}
+
+pub struct Spool {
+    config: Spooler,
+    dir: Option<PathBuf>,
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+impl Spool {
+    /// Create a spooler, reloading any messages left on disk by a previous run in `Disk` mode.
+    pub fn new(config: &Spooler, root: &Path) -> Result<Spool> {
+        let mut queue = Queue {
+            messages: VecDeque::new(),
+            size: 0,
+            next_id: 0,
+        };
+        let dir = match config.storage_type {
+            SpoolerStorageType::Memory => None,
+            SpoolerStorageType::Disk => {
+                let dir = root.join(SPOOL_DIR);
+                fs::create_dir_all(&dir).context("Failed to create spooler directory.")?;
+                Some(dir)
+            }
+        };
+        if let Some(dir) = &dir {
+            let mut ids = vec![];
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|s| s.to_str()?.parse::<u64>().ok())
+                {
+                    ids.push(id);
+                }
+            }
+            ids.sort_unstable();
+            for id in ids {
+                let path = message_path(dir, id);
+                let message = fs::read(&path)
+                    .map_err(Error::from)
+                    .and_then(|data| Ok(serde_json::from_slice::<SpooledMessage>(&data)?))
+                    .and_then(PublishRequest::try_from);
+                match message {
+                    Ok(publish) => {
+                        queue.size += message_size(&publish);
+                        queue.messages.push_back((id, publish));
+                    }
+                    Err(e) => {
+                        warn!("Dropping unreadable spooled message {:?}: {}", path, e);
+                        let _ = fs::remove_file(&path);
+                    }
+                }
+                queue.next_id = id + 1;
+            }
+            debug!("Reloaded {} spooled messages", queue.messages.len());
+        }
+        Ok(Spool {
+            config: config.clone(),
+            dir,
+            queue: Mutex::new(queue),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Add a message to the end of the spooler, evicting QoS 0 messages if the spooler is full.
+    pub fn add_message(&self, publish: PublishRequest) -> Result<u64> {
+        let size = message_size(&publish);
+        if size > self.config.max_size_in_bytes {
+            bail!("Message is larger than the spooler size limit.");
+        }
+        let mut queue = self.queue.lock().unwrap();
+        while queue.size + size > self.config.max_size_in_bytes {
+            let oldest_qos0 = queue
+                .messages
+                .iter()
+                .position(|(_, p)| p.qos == QoS::AtMostOnce);
+            match oldest_qos0 {
+                Some(index) => {
+                    let (id, dropped) = queue.messages.remove(index).unwrap();
+                    queue.size -= message_size(&dropped);
+                    self.remove_file(id);
+                    debug!(
+                        "Spooler is full, dropped QoS 0 message to {}",
+                        dropped.topic
+                    );
+                }
+                None => bail!("Message spool is full. Unable to add message."),
+            }
+        }
+        let id = queue.next_id;
+        if let Some(dir) = &self.dir {
+            let data = serde_json::to_vec(&SpooledMessage::from(&publish))?;
+            fs::write(message_path(dir, id), data).context("Failed to persist spooled message.")?;
+        }
+        queue.next_id += 1;
+        queue.size += size;
+        queue.messages.push_back((id, publish));
+        drop(queue);
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// Wait for the oldest message in the spooler. The message stays spooled until it is removed.
+    pub async fn front(&self) -> (u64, PublishRequest) {
+        loop {
+            if let Some(front) = self.queue.lock().unwrap().messages.front() {
+                return front.clone();
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn remove_message_by_id(&self, id: u64) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(index) = queue.messages.iter().position(|(i, _)| *i == id) {
+            let (_, publish) = queue.messages.remove(index).unwrap();
+            queue.size -= message_size(&publish);
+            self.remove_file(id);
+        }
+    }
+
+    /// Drop every QoS 0 message, called when the connection drops and `keepQos0WhenOffline` is off.
+    pub fn pop_out_messages_with_qos_zero(&self) {
+        if self.config.keep_qos0_when_offline {
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        let (dropped, kept): (VecDeque<_>, VecDeque<_>) = queue
+            .messages
+            .drain(..)
+            .partition(|(_, p)| p.qos == QoS::AtMostOnce);
+        queue.messages = kept;
+        for (id, publish) in dropped {
+            queue.size -= message_size(&publish);
+            self.remove_file(id);
+        }
+    }
+
+    pub fn keep_qos0_when_offline(&self) -> bool {
+        self.config.keep_qos0_when_offline
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove_file(&self, id: u64) {
+        if let Some(dir) = &self.dir {
+            if let Err(e) = fs::remove_file(message_path(dir, id)) {
+                warn!("Failed to remove spooled message {}: {}", id, e);
+            }
+        }
+    }
+}
+
+fn message_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:020}.json"))
+}
+
+fn message_size(publish: &PublishRequest) -> usize {
+    publish.topic.len() + publish.payload.len()
+}
+
+/// Publish spooled messages in order whenever the connection is up.
+///
+/// Each publish first takes tokens from the per-connection transaction and bandwidth limiters.
+/// A message that fails to publish is retried up to `maxPublishRetry` times and then dropped.
+pub async fn drain(
+    spool: Arc<Spool>,
+    client: Client,
+    mut state: watch::Receiver<ConnectionState>,
+    config: Mqtt,
+) {
+    let transaction_limiter = RateLimiter::new(config.max_publishes_per_second);
+    let bandwidth_limiter = RateLimiter::new(config.max_publish_bytes_per_second);
+    let mut retries = 0;
+    loop {
+        while !state.borrow().is_connected() {
+            if state.changed().await.is_err() {
+                return;
+            }
+        }
+        let (id, publish) = spool.front().await;
+        if !state.borrow().is_connected() {
+            continue;
+        }
+        transaction_limiter.acquire(1).await;
+        bandwidth_limiter.acquire(publish.payload.len()).await;
+        debug!("Publishing spooled message {} to {}", id, publish.topic);
+        match with_timeout(config.operation_timeout(), client.publish(publish)).await {
+            Ok(()) => {
+                spool.remove_message_by_id(id);
+                retries = 0;
+            }
+            Err(e) if config.max_publish_retry >= 0 && retries >= config.max_publish_retry => {
+                warn!(
+                    "Dropping spooled message {} after {} retries: {}",
+                    id, retries, e
+                );
+                spool.remove_message_by_id(id);
+                retries = 0;
+            }
+            Err(e) => {
+                warn!("Failed to publish spooled message {}, retrying: {}", id, e);
+                retries += 1;
+                sleep(PUBLISH_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(storage_type: SpoolerStorageType, max_size_in_bytes: usize) -> Spooler {
+        Spooler {
+            storage_type,
+            max_size_in_bytes,
+            keep_qos0_when_offline: false,
+        }
+    }
+
+    fn message(topic: &str, qos: QoS, payload: &'static str) -> PublishRequest {
+        PublishRequest::new(topic, payload).qos(qos)
+    }
+
+    #[test]
+    fn evicts_qos0_when_full() {
+        let spool = Spool::new(&config(SpoolerStorageType::Memory, 10), Path::new(".")).unwrap();
+        spool
+            .add_message(message("a", QoS::AtMostOnce, "1234"))
+            .unwrap();
+        spool
+            .add_message(message("b", QoS::AtLeastOnce, "1234"))
+            .unwrap();
+        spool
+            .add_message(message("c", QoS::AtLeastOnce, "1234"))
+            .unwrap();
+        assert_eq!(spool.len(), 2);
+        assert!(spool
+            .add_message(message("d", QoS::AtLeastOnce, "1234"))
+            .is_err());
+    }
+
+    #[test]
+    fn drops_qos0_when_offline() {
+        let spool = Spool::new(&config(SpoolerStorageType::Memory, 100), Path::new(".")).unwrap();
+        spool
+            .add_message(message("a", QoS::AtMostOnce, "1"))
+            .unwrap();
+        spool
+            .add_message(message("b", QoS::AtLeastOnce, "1"))
+            .unwrap();
+        spool.pop_out_messages_with_qos_zero();
+        assert_eq!(spool.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn disk_spool_survives_restart() {
+        let root = std::env::temp_dir().join(format!("spool-test-{}", std::process::id()));
+        let config = config(SpoolerStorageType::Disk, 100);
+        let spool = Spool::new(&config, &root).unwrap();
+        spool
+            .add_message(message("a", QoS::AtLeastOnce, "first"))
+            .unwrap();
+        let id = spool
+            .add_message(message("b", QoS::AtLeastOnce, "second"))
+            .unwrap();
+        drop(spool);
+
+        let spool = Spool::new(&config, &root).unwrap();
+        assert_eq!(spool.len(), 2);
+        let (first, publish) = spool.front().await;
+        assert_eq!(publish.topic, "a");
+        spool.remove_message_by_id(first);
+        assert_eq!(spool.front().await.0, id);
+        assert!(
+            spool
+                .add_message(message("c", QoS::AtLeastOnce, ""))
+                .unwrap()
+                > id
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
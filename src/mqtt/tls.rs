@@ -0,0 +1,126 @@
+//! TLS setup for the MQTT connection.
+//!
+//! Devices may be provisioned with an RSA key (`BEGIN RSA PRIVATE KEY`), an EC key
+//! (`BEGIN EC PRIVATE KEY`, which gives smaller certificates) or a PKCS#8 key of either kind
+//! (`BEGIN PRIVATE KEY`). The key type is detected from the PEM and the rustls client
+//! configuration is built directly.
+
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use rumqttc::tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rumqttc::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use rumqttc::TlsConfiguration;
+use rustls_pemfile::Item;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    /// PKCS#1 RSA key.
+    Rsa,
+    /// SEC1 EC key.
+    Ec,
+    /// PKCS#8 key, RSA or EC.
+    Pkcs8,
+}
+
+/// Find the private key in a PEM file, returning its type and DER encoding.
+pub fn read_private_key(pem: &[u8]) -> Result<(KeyType, PrivateKeyDer<'static>)> {
+    let text = String::from_utf8_lossy(pem);
+    if text.contains("ENCRYPTED PRIVATE KEY") || text.contains("Proc-Type: 4,ENCRYPTED") {
+        bail!("Encrypted private keys are not supported, please provide an unencrypted key.");
+    }
+    for item in rustls_pemfile::read_all(&mut BufReader::new(pem)) {
+        match item.context("Failed to parse private key PEM.")? {
+            Item::Pkcs1Key(der) => return Ok((KeyType::Rsa, der.into())),
+            Item::Sec1Key(der) => return Ok((KeyType::Ec, der.into())),
+            Item::Pkcs8Key(der) => return Ok((KeyType::Pkcs8, der.into())),
+            _ => {}
+        }
+    }
+    bail!(
+        "No supported private key found. Expected an RSA, EC or PKCS#8 private key in PEM format."
+    )
+}
+
+/// Build the TLS configuration for mutual authentication with AWS IoT Core.
+pub fn tls_configuration(
+    ca: &[u8],
+    cert: &[u8],
+    key: &[u8],
+    alpn: Option<Vec<Vec<u8>>>,
+) -> Result<TlsConfiguration> {
+    let roots = root_store(ca)?;
+    let chain: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut BufReader::new(cert))
+        .collect::<Result<_, _>>()
+        .context("Failed to parse device certificate.")?;
+    if chain.is_empty() {
+        bail!("No device certificate found.");
+    }
+
+    let (key_type, der) = read_private_key(key)?;
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(chain, der)
+        .with_context(|| format!("Unsupported {key_type:?} private key."))?;
+    if let Some(alpn) = alpn {
+        config.alpn_protocols = alpn;
+    }
+    Ok(TlsConfiguration::Rustls(Arc::new(config)))
+}
+
+/// Build a TLS configuration that only authenticates the server, for MQTT over WebSocket where
+/// the device authenticates with a signed request instead of its certificate.
+pub fn server_auth_configuration(ca: &[u8]) -> Result<TlsConfiguration> {
+    let config = ClientConfig::builder()
+        .with_root_certificates(root_store(ca)?)
+        .with_no_client_auth();
+    Ok(TlsConfiguration::Rustls(Arc::new(config)))
+}
+
+fn root_store(ca: &[u8]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let ca_certs = rustls_pemfile::certs(&mut BufReader::new(ca))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse root CA.")?;
+    let (added, _) = roots.add_parsable_certificates(ca_certs);
+    if added == 0 {
+        bail!("No valid root CA certificate found.");
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pem(label: &str) -> String {
+        format!("-----BEGIN {label}-----\nAAAA\n-----END {label}-----\n")
+    }
+
+    #[test]
+    fn detects_key_type() {
+        let types = [
+            ("RSA PRIVATE KEY", KeyType::Rsa),
+            ("EC PRIVATE KEY", KeyType::Ec),
+            ("PRIVATE KEY", KeyType::Pkcs8),
+        ];
+        for (label, key_type) in types {
+            let (detected, der) = read_private_key(pem(label).as_bytes()).unwrap();
+            assert_eq!(detected, key_type);
+            assert_eq!(der.secret_der(), &[0, 0, 0]);
+        }
+        // EC keys generated by openssl carry the curve parameters first.
+        let with_params = pem("EC PARAMETERS") + &pem("EC PRIVATE KEY");
+        assert_eq!(
+            read_private_key(with_params.as_bytes()).unwrap().0,
+            KeyType::Ec
+        );
+    }
+
+    #[test]
+    fn rejects_encrypted_and_missing_keys() {
+        assert!(read_private_key(pem("ENCRYPTED PRIVATE KEY").as_bytes()).is_err());
+        assert!(read_private_key(pem("CERTIFICATE").as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! MQTT over WebSocket on port 443.
+//!
+//! AWS IoT Core does not accept client certificates on WebSocket connections, so the upgrade
+//! request is presigned with SigV4 using credentials from the AWS SDK provider chain. The request
+//! is signed again on every connection attempt so rotated credentials are picked up.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Error, Result};
+use aws_sigv4::http_request::{
+    sign, SignableBody, SignableRequest, SignatureLocation, SigningParams, SigningSettings,
+};
+use aws_types::credentials::ProvideCredentials;
+use http::{HeaderMap, Method, Uri};
+use reqwest::Url;
+
+use crate::proxy;
+
+pub const PORT: u16 = 443;
+const SERVICE_NAME: &str = "iotdevicegateway";
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Broker address for `endpoint`, as expected by `rumqttc` for WebSocket transports.
+pub fn broker_url(endpoint: &str) -> String {
+    format!("wss://{}/mqtt", endpoint)
+}
+
+/// Presign the WebSocket url `url` for AWS IoT Core in `region`.
+pub async fn presign(url: &str, region: &str) -> Result<String> {
+    let sdk_config = proxy::sdk_config(region).await?;
+    let credentials = match sdk_config.credentials_provider() {
+        Some(provider) => provider.provide_credentials().await?,
+        None => bail!("No AWS credentials available to sign the MQTT WebSocket request."),
+    };
+
+    let mut url = Url::parse(url)?;
+    let host = match url.host_str() {
+        Some(host) => host.to_string(),
+        None => bail!("Broker url {} has no host.", url),
+    };
+    let uri: Uri = format!("https://{}{}", host, url.path()).parse()?;
+    let mut settings = SigningSettings::default();
+    settings.signature_location = SignatureLocation::QueryParams;
+    settings.expires_in = Some(PRESIGN_EXPIRY);
+    let params = SigningParams::builder()
+        .access_key(credentials.access_key_id())
+        .secret_key(credentials.secret_access_key())
+        .region(region)
+        .service_name(SERVICE_NAME)
+        .time(SystemTime::now())
+        .settings(settings)
+        .build()?;
+    let headers = HeaderMap::new();
+    let request = SignableRequest::new(&Method::GET, &uri, &headers, SignableBody::Bytes(&[]));
+    let (mut instructions, _) = sign(request, &params).map_err(Error::msg)?.into_parts();
+
+    let mut query = url.query_pairs_mut();
+    for (name, value) in instructions.take_params().unwrap_or_default() {
+        query.append_pair(name, &value);
+    }
+    // AWS IoT expects the session token to be added after signing.
+    if let Some(token) = credentials.session_token() {
+        query.append_pair("X-Amz-Security-Token", token);
+    }
+    drop(query);
+    Ok(url.to_string())
+}
@@ -0,0 +1,70 @@
+//! Platform detection.
+//!
+//! Reports the operating system and CPU architecture the nucleus runs on, using the names
+//! Greengrass uses in recipes and fleet status updates.
+
+use std::process::Command;
+
+use once_cell::sync::Lazy;
+
+pub static PLATFORM: Lazy<Platform> = Lazy::new(Platform::detect);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    /// `linux`, `windows` or `darwin`.
+    pub os: String,
+    /// `amd64`, `x86`, `aarch64` or `arm`.
+    pub architecture: String,
+    /// Machine hardware name, e.g. `armv7l`, or empty when unknown.
+    pub architecture_detail: String,
+}
+
+impl Platform {
+    pub fn detect() -> Self {
+        Platform {
+            os: os_name(std::env::consts::OS).to_string(),
+            architecture: architecture_name(std::env::consts::ARCH).to_string(),
+            architecture_detail: machine().unwrap_or_default(),
+        }
+    }
+}
+
+fn os_name(os: &str) -> &str {
+    match os {
+        "macos" => "darwin",
+        os => os,
+    }
+}
+
+fn architecture_name(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        arch => arch,
+    }
+}
+
+/// Output of `uname -m`, which distinguishes e.g. ARMv6 from ARMv7.
+fn machine() -> Option<String> {
+    if cfg!(windows) {
+        return None;
+    }
+    let output = Command::new("uname").arg("-m").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let machine = String::from_utf8(output.stdout).ok()?;
+    Some(machine.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_rust_names() {
+        assert_eq!(os_name("macos"), "darwin");
+        assert_eq!(os_name("linux"), "linux");
+        assert_eq!(architecture_name("x86_64"), "amd64");
+        assert_eq!(architecture_name("aarch64"), "aarch64");
+    }
+}
@@ -43,6 +43,21 @@ impl SystemConfiguration {
     }
 }
 
+/// A system configuration rooted in a directory of its own, for tests that use the root path.
+#[cfg(test)]
+pub(crate) fn test_configuration() -> &'static SystemConfiguration {
+    SYSCONFIG.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("nucleus-test-{}", std::process::id()));
+        SystemConfiguration {
+            certificateFilePath: root.join("thingCert.crt"),
+            privateKeyPath: root.join("privKey.key"),
+            rootCaPath: root.join("rootCA.pem"),
+            rootpath: root,
+            thingName: "test-thing".to_string(),
+        }
+    })
+}
+
 /**
  * Updates the system configuration values in kernel config as per the given {@link SystemConfiguration}.
  * @param systemConfiguration {@link SystemConfiguration}
@@ -0,0 +1,291 @@
+//! # Kernel alternatives
+//!
+//! The nucleus is launched from `<root>/alts/current/distro`, where `current` is a symlink to a
+//! launch directory and `distro` a symlink to a nucleus distribution. A service manager is
+//! expected to launch it from there and to launch it again whenever it exits with
+//! `kernel::REQUEST_RESTART`.
+//!
+//! Updating the nucleus goes through these symlinks:
+//! - `new` points to the launch directory of the new distribution while it is prepared;
+//! - right before restarting, `current` moves to `old` and `new` to `current`;
+//! - once the new nucleus is up, `old` is removed;
+//! - if it does not come up, `current` moves to `broken` and `old` back to `current`.
+//!
+//! Every launch while `old` is kept counts as an attempt in `launch-attempts`. A new nucleus that
+//! crashes or fails to start is rolled back by the `old` one's next launch, once it has used up
+//! `MAX_LAUNCH_ATTEMPTS`.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::{debug, info, warn};
+
+const ALTS_DIRECTORY: &str = "alts";
+const CURRENT: &str = "current";
+const OLD: &str = "old";
+const NEW: &str = "new";
+const BROKEN: &str = "broken";
+const INITIAL_LAUNCH_DIRECTORY: &str = "init";
+const DISTRO: &str = "distro";
+const LAUNCH_ATTEMPTS: &str = "launch-attempts";
+/// Launches a new nucleus gets to finish its activation before it is rolled back.
+const MAX_LAUNCH_ATTEMPTS: u32 = 3;
+
+#[doc(alias = "KernelAlternatives")]
+pub struct Alternatives {
+    alts: PathBuf,
+}
+
+impl Alternatives {
+    pub fn new(root: &Path) -> Self {
+        Alternatives {
+            alts: root.join(ALTS_DIRECTORY),
+        }
+    }
+
+    pub fn current(&self) -> PathBuf {
+        self.alts.join(CURRENT)
+    }
+
+    pub fn old(&self) -> PathBuf {
+        self.alts.join(OLD)
+    }
+
+    pub fn new_launch(&self) -> PathBuf {
+        self.alts.join(NEW)
+    }
+
+    pub fn broken(&self) -> PathBuf {
+        self.alts.join(BROKEN)
+    }
+
+    fn launch_attempts(&self) -> PathBuf {
+        self.alts.join(LAUNCH_ATTEMPTS)
+    }
+
+    /**
+     * Create `current` from the distribution the nucleus was launched from, the first time.
+     *
+     * Later launches of a nucleus being activated are counted. Returns whether the nucleus was
+     * rolled back because it did not come up, and the `old` one must be launched instead.
+     */
+    pub fn setup(&self) -> Result<bool> {
+        if fs::symlink_metadata(self.current()).is_ok() {
+            return self.count_launch();
+        }
+        let executable = std::env::current_exe()?;
+        let distro = executable
+            .parent()
+            .context("The nucleus executable has no directory.")?;
+        let launch = self.alts.join(INITIAL_LAUNCH_DIRECTORY);
+        create_launch_directory(&launch, distro)?;
+        relink(&self.current(), &launch)?;
+        info!(
+            "Set up {} for {}.",
+            self.current().display(),
+            distro.display()
+        );
+        Ok(false)
+    }
+
+    /// Count a launch of the nucleus being activated, rolling it back once it used up its attempts.
+    fn count_launch(&self) -> Result<bool> {
+        if !self.is_activating() {
+            return Ok(false);
+        }
+        let attempts = fs::read_to_string(self.launch_attempts())
+            .ok()
+            .and_then(|attempts| attempts.trim().parse::<u32>().ok())
+            .unwrap_or(0)
+            + 1;
+        if attempts <= MAX_LAUNCH_ATTEMPTS {
+            debug!("Launch attempt {} of the new nucleus.", attempts);
+            fs::write(self.launch_attempts(), attempts.to_string())?;
+            return Ok(false);
+        }
+        warn!(
+            "The new nucleus did not come up after {} launches.",
+            MAX_LAUNCH_ATTEMPTS
+        );
+        self.prepare_rollback()?;
+        Ok(true)
+    }
+
+    /// Prepare the launch directory of distribution `distro` for deployment `deployment_id`.
+    #[doc(alias = "prepareBootstrap")]
+    pub fn prepare(&self, deployment_id: &str, distro: &Path) -> Result<()> {
+        let launch = self.alts.join(sanitize(deployment_id));
+        create_launch_directory(&launch, distro)?;
+        relink(&self.new_launch(), &launch)
+    }
+
+    /// Make the prepared distribution the one launched next, keeping the running one as `old`.
+    pub fn activate(&self) -> Result<()> {
+        let new = fs::read_link(self.new_launch()).context("No new nucleus was prepared.")?;
+        relink(&self.old(), &fs::read_link(self.current())?)?;
+        remove(&self.launch_attempts())?;
+        relink(&self.current(), &new)?;
+        fs::remove_file(self.new_launch())?;
+        info!("Activated nucleus {}.", new.display());
+        Ok(())
+    }
+
+    /// Whether a new nucleus was prepared.
+    pub fn is_prepared(&self) -> bool {
+        fs::symlink_metadata(self.new_launch()).is_ok()
+    }
+
+    /// Whether the running nucleus replaced an `old` one that can be rolled back to.
+    pub fn is_activating(&self) -> bool {
+        fs::symlink_metadata(self.old()).is_ok()
+    }
+
+    /// Forget the `old` nucleus once the new one is up.
+    #[doc(alias = "activationSucceeds")]
+    pub fn activation_succeeded(&self) -> Result<()> {
+        remove(&self.launch_attempts())?;
+        self.discard(&self.old())
+    }
+
+    /// Launch the `old` nucleus next, keeping the failed one as `broken`.
+    #[doc(alias = "prepareRollback")]
+    pub fn prepare_rollback(&self) -> Result<()> {
+        let old = fs::read_link(self.old()).context("There is no nucleus to roll back to.")?;
+        relink(&self.broken(), &fs::read_link(self.current())?)?;
+        relink(&self.current(), &old)?;
+        fs::remove_file(self.old())?;
+        remove(&self.launch_attempts())?;
+        info!("Rolling back to nucleus {}.", old.display());
+        Ok(())
+    }
+
+    /// Whether the running nucleus replaced a `broken` one it was rolled back from.
+    pub fn is_rolling_back(&self) -> bool {
+        fs::symlink_metadata(self.broken()).is_ok()
+    }
+
+    /// Forget the `broken` nucleus once the rollback is done.
+    pub fn rollback_completed(&self) -> Result<()> {
+        self.discard(&self.broken())
+    }
+
+    /// Forget a prepared nucleus that will not be activated.
+    pub fn discard_new(&self) -> Result<()> {
+        self.discard(&self.new_launch())
+    }
+
+    /// Remove symlink `link` and the launch directory it points to.
+    fn discard(&self, link: &Path) -> Result<()> {
+        let launch = match fs::read_link(link) {
+            Ok(launch) => launch,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        fs::remove_file(link)?;
+        if fs::read_link(self.current()).ok().as_ref() != Some(&launch) {
+            debug!("Removing {}.", launch.display());
+            fs::remove_dir_all(&launch)?;
+        }
+        Ok(())
+    }
+}
+
+fn create_launch_directory(launch: &Path, distro: &Path) -> Result<()> {
+    fs::create_dir_all(launch)?;
+    relink(&launch.join(DISTRO), distro)
+}
+
+/// Remove file `path`, if it is there.
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Point symlink `link` to `target`, replacing whatever `link` was.
+fn relink(link: &Path, target: &Path) -> Result<()> {
+    remove(link)?;
+    symlink(target, link)
+        .with_context(|| format!("Failed to link {} to {}.", link.display(), target.display()))
+}
+
+/// Deployment ids are ARNs, which do not make good directory names.
+fn sanitize(deployment_id: &str) -> String {
+    deployment_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activates_and_rolls_back() {
+        let root = std::env::temp_dir().join(format!("alts-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let distro = |name: &str| {
+            let distro = root.join(name);
+            fs::create_dir_all(&distro).unwrap();
+            distro
+        };
+        let (first, second) = (distro("first"), distro("second"));
+        let alts = Alternatives::new(&root);
+        let launched = |link: PathBuf| fs::read_link(link.join(DISTRO)).unwrap();
+        alts.setup().unwrap();
+
+        alts.prepare("deployment-1", &first).unwrap();
+        alts.activate().unwrap();
+        assert_eq!(launched(alts.current()), first);
+        alts.activation_succeeded().unwrap();
+        assert!(!alts.is_activating());
+
+        alts.prepare("arn:deployment:2", &second).unwrap();
+        alts.activate().unwrap();
+        assert!(alts.is_activating());
+        assert_eq!(launched(alts.current()), second);
+        alts.prepare_rollback().unwrap();
+        assert_eq!(launched(alts.current()), first);
+        alts.rollback_completed().unwrap();
+        assert!(fs::symlink_metadata(alts.broken()).is_err());
+        assert!(!alts.alts.join("arn_deployment_2").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rolls_back_a_nucleus_that_does_not_come_up() {
+        let root = std::env::temp_dir().join(format!("alts-launch-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let distro = root.join("distro");
+        fs::create_dir_all(&distro).unwrap();
+        let alts = Alternatives::new(&root);
+        assert!(!alts.setup().unwrap());
+        let first = fs::read_link(alts.current()).unwrap();
+
+        alts.prepare("deployment-1", &distro).unwrap();
+        alts.activate().unwrap();
+        for _ in 0..MAX_LAUNCH_ATTEMPTS {
+            assert!(!alts.setup().unwrap());
+        }
+        assert!(alts.setup().unwrap());
+        assert_eq!(fs::read_link(alts.current()).unwrap(), first);
+        assert!(alts.is_rolling_back());
+        assert!(!alts.is_activating());
+        assert!(!alts.launch_attempts().exists());
+        // The old nucleus launches as usual.
+        assert!(!alts.setup().unwrap());
+        alts.rollback_completed().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
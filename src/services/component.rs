@@ -0,0 +1,1010 @@
+//! # Component lifecycle
+//!
+//! Installs, starts, stops and removes the components deployed on the device. A component is
+//! described by its recipe, and the lifecycle of the first manifest matching this platform runs
+//! in the configured POSIX shell:
+//! - `bootstrap` runs first for new or updated components, and may ask for the nucleus or the
+//!   device to restart before the update goes on;
+//! - `install` runs before the component starts and must succeed;
+//! - `run` is the process of the component, which is `RUNNING` until it exits;
+//! - `shutdown` runs when the component is stopped, after its process was killed.
+//!
+//! Scripts can refer to `{artifacts:path}`, `{work:path}`, `{kernel:rootPath}`,
+//! `{iot:thingName}` and `{configuration:/json/pointer}`.
+//!
+//! # Configuration
+//! Each component runs with a configuration built from the `DefaultConfiguration` of its recipe
+//! and the `configurationUpdate` of the deployments. Changes are sent on
+//! `CONFIGURATION_CHANGES`; components whose scripts use their configuration are restarted.
+//!
+//! # Layout
+//! - `<root>/packages/artifacts/<name>/<version>/`: the downloaded artifacts.
+//! - `<root>/work/<name>/`: the working directory of the component.
+//! - `<root>/deployments/components.json`: the components installed by deployments, which are
+//!   started again when the nucleus starts.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::process::Command;
+use tokio::sync::{broadcast, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{debug, error, info, warn};
+
+use crate::dependency::State;
+use crate::platform::PLATFORM;
+use crate::services::{ipc, kernel, report_state, ServiceStatus, SERVICES};
+use crate::{config, provisioning};
+
+const DEFAULT_BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_INSTALL_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_POSIX_SHELL: &str = "sh";
+const CONFIGURATION_PREFIX: &str = "{configuration:";
+const DEPLOYED_COMPONENTS_FILE: &str = "components.json";
+/// How long started components must stay up for an update to succeed.
+const STARTUP_GRACE: Duration = Duration::from_secs(5);
+
+/// Components installed by deployments, by name.
+static COMPONENTS: Lazy<Mutex<HashMap<String, Installed>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Held by tests that change the deployed components.
+#[cfg(test)]
+pub(crate) static TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Configuration changes of deployed components, for listeners such as running components.
+pub static CONFIGURATION_CHANGES: Lazy<broadcast::Sender<ConfigurationChange>> =
+    Lazy::new(|| broadcast::channel(64).0);
+
+#[derive(Debug, Clone)]
+pub struct ConfigurationChange {
+    pub component: String,
+    pub configuration: Value,
+}
+
+struct Installed {
+    component: Component,
+    process: Option<Process>,
+}
+
+/// The `run` script of a component.
+struct Process {
+    stop: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    pub script: String,
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lifecycle {
+    /// Runs before the others when the component is new or updated, and may ask for a restart.
+    pub bootstrap: Option<Script>,
+    pub install: Option<Script>,
+    pub run: Option<Script>,
+    pub shutdown: Option<Script>,
+}
+
+impl Lifecycle {
+    fn uses_configuration(&self) -> bool {
+        [&self.bootstrap, &self.install, &self.run, &self.shutdown]
+            .into_iter()
+            .flatten()
+            .any(|script| script.script.contains(CONFIGURATION_PREFIX))
+    }
+}
+
+/// The parts of a component recipe the nucleus acts on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Recipe {
+    pub name: String,
+    pub version: String,
+    pub dependencies: Vec<String>,
+    /// Artifact URIs of the selected manifest.
+    pub artifacts: Vec<String>,
+    pub lifecycle: Lifecycle,
+    pub default_configuration: Value,
+}
+
+/// A component to deploy: its recipe and the configuration it runs with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Component {
+    pub recipe: Recipe,
+    pub configuration: Value,
+}
+
+/// The `configurationUpdate` of a component in a deployment document.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConfigurationUpdate {
+    /// RFC 6901 JSON pointers to reset to their default value, `""` being the whole configuration.
+    #[serde(default)]
+    pub reset: Vec<String>,
+    /// JSON to merge into the configuration, possibly as a string.
+    #[serde(default)]
+    pub merge: Option<Value>,
+}
+
+impl ConfigurationUpdate {
+    /**
+     * The configuration resulting from this update of `current`.
+     *
+     * Keys missing from `current` take their value in `default` first. The resets are then
+     * applied, followed by the merge.
+     */
+    pub fn apply(&self, current: &Value, default: &Value) -> Result<Value> {
+        let mut configuration = default.clone();
+        merge(&mut configuration, current);
+        for pointer in &self.reset {
+            if pointer.is_empty() {
+                configuration = default.clone();
+                continue;
+            }
+            set_pointer(
+                &mut configuration,
+                pointer,
+                default.pointer(pointer).cloned(),
+            )
+            .with_context(|| format!("Failed to reset {pointer}."))?;
+        }
+        match &self.merge {
+            Some(Value::String(json)) => merge(
+                &mut configuration,
+                &serde_json::from_str(json).context("Failed to deserialize merge.")?,
+            ),
+            Some(value) => merge(&mut configuration, value),
+            None => {}
+        }
+        Ok(configuration)
+    }
+}
+
+/// Merge `patch` into `target`, recursing into objects and replacing anything else.
+fn merge(target: &mut Value, patch: &Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch.clone(),
+    }
+}
+
+/// Set the value at a JSON `pointer`, creating missing parents, or remove it if `value` is `None`.
+fn set_pointer(target: &mut Value, pointer: &str, value: Option<Value>) -> Result<()> {
+    let (parent, key) = pointer
+        .rsplit_once('/')
+        .with_context(|| format!("Invalid JSON pointer {pointer}."))?;
+    let key = key.replace("~1", "/").replace("~0", "~");
+    if target.pointer(parent).is_none() {
+        if value.is_none() {
+            return Ok(());
+        }
+        set_pointer(target, parent, Some(Value::Object(Default::default())))?;
+    }
+    match (target.pointer_mut(parent), value) {
+        (Some(Value::Object(map)), Some(value)) => {
+            map.insert(key, value);
+        }
+        (Some(Value::Object(map)), None) => {
+            map.remove(&key);
+        }
+        (Some(Value::Array(array)), value) => {
+            let index: usize = key
+                .parse()
+                .with_context(|| format!("Invalid array index in {pointer}."))?;
+            match value {
+                Some(value) if index < array.len() => array[index] = value,
+                Some(value) if index == array.len() => array.push(value),
+                None if index < array.len() => {
+                    array.remove(index);
+                }
+                _ => {}
+            }
+        }
+        _ => bail!("Parent of {pointer} is not an object or array."),
+    }
+    Ok(())
+}
+
+impl Recipe {
+    /// Parse a JSON recipe, selecting the first manifest that matches this platform.
+    pub fn from_json(recipe: &Value) -> Result<Self> {
+        let name = recipe["ComponentName"]
+            .as_str()
+            .context("Recipe has no component name.")?;
+        let version = recipe["ComponentVersion"]
+            .as_str()
+            .context("Recipe has no component version.")?;
+        let manifests = recipe["Manifests"].as_array().cloned().unwrap_or_default();
+        let manifest = manifests
+            .iter()
+            .find(|manifest| platform_matches(&manifest["Platform"]));
+        if manifest.is_none() && !manifests.is_empty() {
+            bail!(
+                "Component {} has no manifest for {} {}.",
+                name,
+                PLATFORM.os,
+                PLATFORM.architecture
+            );
+        }
+        let manifest = manifest.cloned().unwrap_or_default();
+        // The lifecycle is usually in the manifest, but may also be at the top of the recipe.
+        let lifecycle = match &manifest["Lifecycle"] {
+            Value::Object(_) => &manifest["Lifecycle"],
+            _ => &recipe["Lifecycle"],
+        };
+        Ok(Recipe {
+            name: name.to_string(),
+            version: version.to_string(),
+            dependencies: recipe["ComponentDependencies"]
+                .as_object()
+                .map(|dependencies| dependencies.keys().cloned().collect())
+                .unwrap_or_default(),
+            artifacts: manifest["Artifacts"]
+                .as_array()
+                .map(|artifacts| {
+                    artifacts
+                        .iter()
+                        .filter_map(|artifact| artifact["Uri"].as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            lifecycle: Lifecycle {
+                bootstrap: script(lifecycle, "bootstrap"),
+                install: script(lifecycle, "install"),
+                run: script(lifecycle, "run"),
+                shutdown: script(lifecycle, "shutdown"),
+            },
+            default_configuration: match &recipe["ComponentConfiguration"]["DefaultConfiguration"] {
+                Value::Null => json!({}),
+                configuration => configuration.clone(),
+            },
+        })
+    }
+}
+
+fn platform_matches(platform: &Value) -> bool {
+    let matches = |key: &str, value: &str| match platform[key].as_str() {
+        None | Some("*") | Some("all") => true,
+        Some(wanted) => wanted.eq_ignore_ascii_case(value),
+    };
+    matches("os", &PLATFORM.os) && matches("architecture", &PLATFORM.architecture)
+}
+
+/// Lifecycle steps are either a script or an object with a `Script` and a `Timeout`.
+fn script(lifecycle: &Value, step: &str) -> Option<Script> {
+    let (_, value) = lifecycle
+        .as_object()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(step))?;
+    match value {
+        Value::String(script) => Some(Script {
+            script: script.clone(),
+            timeout: None,
+        }),
+        Value::Object(_) => Some(Script {
+            script: value["Script"].as_str()?.to_string(),
+            timeout: value["Timeout"].as_u64().map(Duration::from_secs),
+        }),
+        _ => None,
+    }
+}
+
+pub(crate) fn root() -> PathBuf {
+    provisioning::SYSCONFIG
+        .get()
+        .map_or_else(|| PathBuf::from("."), |system| system.rootpath.clone())
+}
+
+pub fn artifacts_path(name: &str, version: &str) -> PathBuf {
+    root()
+        .join("packages")
+        .join("artifacts")
+        .join(name)
+        .join(version)
+}
+
+pub fn work_path(name: &str) -> PathBuf {
+    root().join("work").join(name)
+}
+
+fn deployed_components_path() -> PathBuf {
+    root().join("deployments").join(DEPLOYED_COMPONENTS_FILE)
+}
+
+fn interpolate(script: &str, component: &Component) -> String {
+    let recipe = &component.recipe;
+    let thing_name = provisioning::SYSCONFIG
+        .get()
+        .map(|system| system.thingName.as_str())
+        .unwrap_or_default();
+    let script = script
+        .replace(
+            "{artifacts:path}",
+            &artifacts_path(&recipe.name, &recipe.version).to_string_lossy(),
+        )
+        .replace("{work:path}", &work_path(&recipe.name).to_string_lossy())
+        .replace("{kernel:rootPath}", &root().to_string_lossy())
+        .replace("{iot:thingName}", thing_name);
+    // "{configuration:/json/pointer}", strings without their quotes. Values are not expanded
+    // again, so that a value referring to the configuration cannot expand forever.
+    let mut interpolated = String::with_capacity(script.len());
+    let mut rest = script.as_str();
+    while let Some(start) = rest.find(CONFIGURATION_PREFIX) {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        interpolated.push_str(&rest[..start]);
+        let pointer = &rest[start + CONFIGURATION_PREFIX.len()..end];
+        match component.configuration.pointer(pointer) {
+            Some(Value::String(value)) => interpolated.push_str(value),
+            Some(value) => interpolated.push_str(&value.to_string()),
+            None => {}
+        }
+        rest = &rest[end + 1..];
+    }
+    interpolated.push_str(rest);
+    interpolated
+}
+
+fn command(script: &Script, component: &Component) -> Command {
+    let shell = config::CONFIG
+        .get()
+        .and_then(|config| {
+            config.services.kernel.configuration.run_with_default["posixShell"]
+                .as_str()
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| DEFAULT_POSIX_SHELL.to_string());
+    let mut command = Command::new(shell);
+    command
+        .arg("-c")
+        .arg(interpolate(&script.script, component))
+        .current_dir(work_path(&component.recipe.name))
+        .env(ipc::SOCKET_PATH_ENV, ipc::socket_path())
+        .env(ipc::TOKEN_ENV, ipc::token(&component.recipe.name))
+        .kill_on_drop(true);
+    command
+}
+
+/// Run a lifecycle script to completion, killing it after its timeout.
+async fn run_script(
+    script: &Script,
+    component: &Component,
+    default_timeout: Duration,
+) -> Result<()> {
+    let status = script_status(script, component, default_timeout).await?;
+    if !status.success() {
+        bail!("Script exited with {}.", status);
+    }
+    Ok(())
+}
+
+async fn script_status(
+    script: &Script,
+    component: &Component,
+    default_timeout: Duration,
+) -> Result<ExitStatus> {
+    let limit = script.timeout.unwrap_or(default_timeout);
+    let status = timeout(limit, command(script, component).status())
+        .await
+        .with_context(|| format!("Script timed out after {} seconds.", limit.as_secs()))??;
+    Ok(status)
+}
+
+/**
+ * Run the `bootstrap` steps of the new or updated `components`, in dependency order.
+ *
+ * A step exits with `kernel::REQUEST_RESTART` or `kernel::REQUEST_REBOOT` to ask for the nucleus
+ * or the device to restart before the update goes on; the strongest request is returned. Any
+ * other non-zero exit fails the update.
+ *
+ * The update can be `canceled` before each step, in which case `bootstrap` fails with `Canceled`.
+ */
+pub async fn bootstrap(components: &[Component], canceled: &AtomicBool) -> Result<Option<i32>> {
+    let mut restart = None;
+    for component in start_order(components)? {
+        let recipe = &component.recipe;
+        let Some(script) = &recipe.lifecycle.bootstrap else {
+            continue;
+        };
+        let installed = COMPONENTS
+            .lock()
+            .unwrap()
+            .get(&recipe.name)
+            .is_some_and(|installed| installed.component.recipe == *recipe);
+        if installed {
+            continue;
+        }
+        if canceled.load(Ordering::SeqCst) {
+            bail!(Canceled);
+        }
+        info!(
+            "Bootstrapping component {} {}.",
+            recipe.name, recipe.version
+        );
+        std::fs::create_dir_all(work_path(&recipe.name))?;
+        let status = script_status(script, component, DEFAULT_BOOTSTRAP_TIMEOUT)
+            .await
+            .with_context(|| format!("Failed to bootstrap component {}.", recipe.name))?;
+        match status.code() {
+            Some(0) => {}
+            Some(code @ (kernel::REQUEST_RESTART | kernel::REQUEST_REBOOT)) => {
+                restart = restart.max(Some(code))
+            }
+            _ => bail!(
+                "Bootstrap of component {} exited with {}.",
+                recipe.name,
+                status
+            ),
+        }
+    }
+    Ok(restart)
+}
+
+/// Names of the components installed by deployments.
+pub fn deployed() -> Vec<String> {
+    COMPONENTS.lock().unwrap().keys().cloned().collect()
+}
+
+/// Current configuration of deployed component `name`.
+pub fn configuration(name: &str) -> Option<Value> {
+    COMPONENTS
+        .lock()
+        .unwrap()
+        .get(name)
+        .map(|installed| installed.component.configuration.clone())
+}
+
+/// Returned by `bootstrap` and `apply` when they stopped at a safe point because the update was
+/// canceled.
+#[derive(Debug)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The update was canceled.")
+    }
+}
+
+impl std::error::Error for Canceled {}
+
+/// The components installed by deployments at some point in time, to roll back to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Snapshot {
+    components: Vec<Component>,
+}
+
+pub fn snapshot() -> Snapshot {
+    let components = COMPONENTS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|installed| installed.component.clone())
+        .collect();
+    Snapshot { components }
+}
+
+impl Snapshot {
+    /// Bring back the components of the snapshot after a failed update to `attempted`.
+    pub async fn restore(self, attempted: &[Component]) -> Result<()> {
+        for component in attempted {
+            let name = &component.recipe.name;
+            if !self.components.iter().any(|c| &c.recipe.name == name) {
+                remove(name).await;
+            }
+        }
+        apply(self.components, &AtomicBool::new(false)).await
+    }
+}
+
+/// Keep the installed components in the deployed components file, for the next start.
+fn save() {
+    let path = deployed_components_path();
+    // Write then rename, so that a crash never leaves a partial file behind.
+    let temporary = path.with_extension("tmp");
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .map_err(anyhow::Error::from)
+        .and_then(|()| Ok(fs::write(&temporary, serde_json::to_vec(&snapshot())?)?))
+        .and_then(|()| Ok(fs::rename(&temporary, &path)?));
+    if let Err(e) = result {
+        warn!("Failed to save the deployed components: {:#}", e);
+    }
+}
+
+/// Start the components deployed when the nucleus last stopped.
+pub async fn start_deployed() {
+    let path = deployed_components_path();
+    let snapshot: Snapshot = match fs::read(&path) {
+        Ok(data) => match serde_json::from_slice(&data) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("Ignoring invalid deployed components: {}", e);
+                return;
+            }
+        },
+        Err(_) => return,
+    };
+    if snapshot.components.is_empty() {
+        return;
+    }
+    info!(
+        "Starting {} deployed components.",
+        snapshot.components.len()
+    );
+    if let Err(e) = apply(snapshot.components, &AtomicBool::new(false)).await {
+        error!("Failed to start the deployed components: {:#}", e);
+    }
+}
+
+fn is_broken(name: &str) -> bool {
+    SERVICES
+        .get(name)
+        .is_some_and(|service| *service.status() == State::BROKEN)
+}
+
+/// Order `components` so that every component comes after the ones it depends on.
+pub fn start_order(components: &[Component]) -> Result<Vec<&Component>> {
+    let names: HashSet<&str> = components.iter().map(|c| c.recipe.name.as_str()).collect();
+    let mut ordered: Vec<&Component> = vec![];
+    let mut done = HashSet::new();
+    while ordered.len() < components.len() {
+        let before = ordered.len();
+        for component in components {
+            let recipe = &component.recipe;
+            let ready = recipe
+                .dependencies
+                .iter()
+                .filter(|dependency| names.contains(dependency.as_str()))
+                .all(|dependency| done.contains(dependency.as_str()));
+            if ready && !done.contains(recipe.name.as_str()) {
+                done.insert(recipe.name.as_str());
+                ordered.push(component);
+            }
+        }
+        if ordered.len() == before {
+            bail!("Components have circular dependencies.");
+        }
+    }
+    Ok(ordered)
+}
+
+/**
+ * Make the components on the device match `components`.
+ *
+ * Every component is installed first, then components that are no longer wanted are removed,
+ * and finally new or updated components are started in dependency order. Any failure fails
+ * the whole update, including a started component breaking within `STARTUP_GRACE`.
+ *
+ * A component whose recipe is unchanged but whose configuration changed keeps running; it is
+ * only restarted when its lifecycle refers to its configuration.
+ *
+ * The update can be `canceled` until components start changing, in which case `apply` fails
+ * with `Canceled` and leaves them as they were.
+ *
+ * The resulting components are saved, to be started again by `start_deployed`.
+ */
+pub async fn apply(components: Vec<Component>, canceled: &AtomicBool) -> Result<()> {
+    let applied = apply_components(components, canceled).await;
+    save();
+    applied
+}
+
+async fn apply_components(components: Vec<Component>, canceled: &AtomicBool) -> Result<()> {
+    let ordered = start_order(&components)?;
+    let wanted: HashSet<&str> = components.iter().map(|c| c.recipe.name.as_str()).collect();
+
+    // The components to install, or only to reconfigure, in dependency order.
+    let mut updates = vec![];
+    for component in ordered {
+        let recipe = &component.recipe;
+        let current = COMPONENTS
+            .lock()
+            .unwrap()
+            .get(&recipe.name)
+            .map(|installed| installed.component.clone());
+        match current {
+            Some(current) if current.recipe == *recipe && !is_broken(&recipe.name) => {
+                if current.configuration == component.configuration {
+                    debug!(
+                        "Component {} {} is up to date.",
+                        recipe.name, recipe.version
+                    );
+                    continue;
+                }
+                updates.push((component, false));
+            }
+            _ => updates.push((component, true)),
+        }
+    }
+
+    // Last safe point, nothing was changed yet.
+    if canceled.load(Ordering::SeqCst) {
+        bail!(Canceled);
+    }
+    let mut changed = vec![];
+    for (component, reinstall) in updates {
+        let recipe = &component.recipe;
+        if reinstall {
+            install(component)
+                .await
+                .with_context(|| format!("Failed to install component {}.", recipe.name))?;
+        } else {
+            update_configuration(component);
+            if !recipe.lifecycle.uses_configuration() {
+                continue;
+            }
+        }
+        changed.push(component);
+    }
+
+    for name in deployed() {
+        if !wanted.contains(name.as_str()) {
+            remove(&name).await;
+        }
+    }
+
+    for component in &changed {
+        let name = &component.recipe.name;
+        stop(name).await;
+        start((*component).clone())
+            .with_context(|| format!("Failed to start component {}.", name))?;
+    }
+
+    if changed
+        .iter()
+        .any(|component| component.recipe.lifecycle.run.is_some())
+    {
+        sleep(STARTUP_GRACE).await;
+        if let Some(component) = changed
+            .iter()
+            .find(|component| is_broken(&component.recipe.name))
+        {
+            bail!("Component {} broke after starting.", component.recipe.name);
+        }
+    }
+    Ok(())
+}
+
+/// Record the new configuration of a running component and let listeners know about it.
+fn update_configuration(component: &Component) {
+    let name = &component.recipe.name;
+    if let Some(installed) = COMPONENTS.lock().unwrap().get_mut(name) {
+        installed.component.configuration = component.configuration.clone();
+    }
+    info!("Configuration of component {} changed.", name);
+    // Nobody listening is fine.
+    let _ = CONFIGURATION_CHANGES.send(ConfigurationChange {
+        component: name.clone(),
+        configuration: component.configuration.clone(),
+    });
+}
+
+async fn install(component: &Component) -> Result<()> {
+    let recipe = &component.recipe;
+    std::fs::create_dir_all(work_path(&recipe.name))?;
+    if !SERVICES.contains_key(&recipe.name) {
+        SERVICES.insert(
+            recipe.name.clone(),
+            ServiceStatus::component(&recipe.name, &recipe.version),
+        );
+    }
+    if let Some(script) = &recipe.lifecycle.install {
+        info!("Installing component {} {}.", recipe.name, recipe.version);
+        if let Err(e) = run_script(script, component, DEFAULT_INSTALL_TIMEOUT).await {
+            report_state(&recipe.name, State::BROKEN);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Start the `run` script of an installed component and watch it until it exits or is stopped.
+fn start(component: Component) -> Result<()> {
+    let recipe = &component.recipe;
+    SERVICES.insert(
+        recipe.name.clone(),
+        ServiceStatus::component(&recipe.name, &recipe.version),
+    );
+    report_state(&recipe.name, State::INSTALLED);
+    let process = match &recipe.lifecycle.run {
+        Some(script) => {
+            info!("Starting component {} {}.", recipe.name, recipe.version);
+            report_state(&recipe.name, State::STARTING);
+            let child = command(script, &component).spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(e) => {
+                    report_state(&recipe.name, State::BROKEN);
+                    return Err(e.into());
+                }
+            };
+            report_state(&recipe.name, State::RUNNING);
+            let stop = Arc::new(Notify::new());
+            let stopped = stop.clone();
+            let name = recipe.name.clone();
+            let task = tokio::spawn(async move {
+                tokio::select! {
+                    status = child.wait() => match status {
+                        Ok(status) if status.success() => report_state(&name, State::FINISHED),
+                        Ok(status) => {
+                            error!("Component {} exited with {}.", name, status);
+                            report_state(&name, State::BROKEN);
+                        }
+                        Err(e) => {
+                            error!("Failed to wait for component {}: {}", name, e);
+                            report_state(&name, State::BROKEN);
+                        }
+                    },
+                    _ = stopped.notified() => {
+                        if let Err(e) = child.kill().await {
+                            warn!("Failed to kill component {}: {}", name, e);
+                        }
+                    }
+                }
+            });
+            Some(Process { stop, task })
+        }
+        None => {
+            report_state(&recipe.name, State::FINISHED);
+            None
+        }
+    };
+    COMPONENTS.lock().unwrap().insert(
+        component.recipe.name.clone(),
+        Installed { component, process },
+    );
+    Ok(())
+}
+
+/// Stop component `name`, killing its process and running its `shutdown` script.
+async fn stop(name: &str) {
+    let (component, process) = match COMPONENTS.lock().unwrap().get_mut(name) {
+        Some(installed) => (installed.component.clone(), installed.process.take()),
+        None => return,
+    };
+    info!("Stopping component {}.", name);
+    report_state(name, State::STOPPING);
+    if let Some(process) = process {
+        process.stop.notify_one();
+        let _ = process.task.await;
+    }
+    if let Some(script) = &component.recipe.lifecycle.shutdown {
+        if let Err(e) = run_script(script, &component, DEFAULT_SHUTDOWN_TIMEOUT).await {
+            warn!("Shutdown of component {} failed: {:#}", name, e);
+        }
+    }
+    ipc::revoke(name);
+    report_state(name, State::FINISHED);
+}
+
+/// Stop every installed component, dependents first, and wait for their processes to exit.
+pub async fn stop_all() {
+    let components: Vec<Component> = snapshot().components;
+    let ordered = start_order(&components).unwrap_or_else(|_| components.iter().collect());
+    for component in ordered.into_iter().rev() {
+        stop(&component.recipe.name).await;
+    }
+}
+
+/// Stop and forget component `name`, which is no longer part of any deployment.
+async fn remove(name: &str) {
+    stop(name).await;
+    info!("Removing component {}.", name);
+    COMPONENTS.lock().unwrap().remove(name);
+    SERVICES.remove(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_recipe() {
+        let recipe = json!({
+            "ComponentName": "com.example.Hello",
+            "ComponentVersion": "1.0.0",
+            "ComponentDependencies": { "com.example.Base": { "VersionRequirement": ">=1.0.0" } },
+            "Manifests": [
+                { "Platform": { "os": "no-such-os" }, "Lifecycle": { "Run": "wrong" } },
+                {
+                    "Platform": { "os": "*" },
+                    "Lifecycle": {
+                        "Install": { "Script": "pip3 install -r requirements.txt", "Timeout": 300 },
+                        "Run": "python3 {artifacts:path}/hello.py"
+                    },
+                    "Artifacts": [{ "Uri": "s3://bucket/hello.py" }]
+                }
+            ]
+        });
+        let recipe = Recipe::from_json(&recipe).unwrap();
+        assert_eq!(recipe.dependencies, vec!["com.example.Base"]);
+        assert_eq!(recipe.artifacts, vec!["s3://bucket/hello.py"]);
+        assert_eq!(
+            recipe.lifecycle.install.unwrap().timeout,
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            recipe.lifecycle.run.unwrap().script,
+            "python3 {artifacts:path}/hello.py"
+        );
+        assert!(recipe.lifecycle.shutdown.is_none());
+    }
+
+    #[test]
+    fn orders_by_dependencies() {
+        let recipe = |name: &str, dependencies: &[&str]| Component {
+            recipe: Recipe {
+                name: name.to_string(),
+                version: "1.0.0".to_string(),
+                dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+                artifacts: vec![],
+                lifecycle: Lifecycle::default(),
+                default_configuration: json!({}),
+            },
+            configuration: json!({}),
+        };
+        let recipes = vec![
+            recipe("app", &["lib", "aws.greengrass.Nucleus"]),
+            recipe("lib", &["base"]),
+            recipe("base", &[]),
+        ];
+        let names: Vec<&str> = start_order(&recipes)
+            .unwrap()
+            .iter()
+            .map(|c| c.recipe.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["base", "lib", "app"]);
+
+        let cycle = vec![recipe("a", &["b"]), recipe("b", &["a"])];
+        assert!(start_order(&cycle).is_err());
+    }
+
+    #[test]
+    fn updates_configuration() {
+        let default = json!({ "port": 8080, "log": { "level": "INFO", "file": "out.log" } });
+        let current = json!({ "port": 9090, "log": { "level": "DEBUG" }, "extra": true });
+        let update: ConfigurationUpdate = serde_json::from_value(json!({
+            "reset": ["/log/level", "/extra"],
+            "merge": "{\"port\": 7070, \"log\": {\"file\": \"app.log\"}}"
+        }))
+        .unwrap();
+        let configuration = update.apply(&current, &default).unwrap();
+        assert_eq!(
+            configuration,
+            json!({ "port": 7070, "log": { "level": "INFO", "file": "app.log" } })
+        );
+
+        let reset_all = ConfigurationUpdate {
+            reset: vec!["".to_string()],
+            merge: None,
+        };
+        assert_eq!(reset_all.apply(&current, &default).unwrap(), default);
+
+        let component = Component {
+            recipe: Recipe::from_json(&json!({
+                "ComponentName": "com.example.Hello",
+                "ComponentVersion": "1.0.0"
+            }))
+            .unwrap(),
+            configuration,
+        };
+        assert_eq!(
+            interpolate(
+                "serve {configuration:/port} {configuration:/log/file}",
+                &component
+            ),
+            "serve 7070 app.log"
+        );
+
+        let component = Component {
+            configuration: json!({
+                "self": "{configuration:/self}",
+                "a": "{configuration:/b}",
+                "b": "{configuration:/a}"
+            }),
+            ..component
+        };
+        assert_eq!(
+            interpolate(
+                "echo {configuration:/self} {configuration:/a} {configuration:/missing}",
+                &component
+            ),
+            "echo {configuration:/self} {configuration:/b} "
+        );
+    }
+
+    #[tokio::test]
+    async fn cancels_before_changing_components() {
+        let _lock = TEST_LOCK.lock().await;
+        provisioning::test_configuration();
+        let component = |name: &str, configuration: Value, bootstrap: Option<&str>| Component {
+            recipe: Recipe {
+                name: name.to_string(),
+                version: "1.0.0".to_string(),
+                dependencies: vec![],
+                artifacts: vec![],
+                lifecycle: Lifecycle {
+                    bootstrap: bootstrap.map(|script| Script {
+                        script: script.to_string(),
+                        timeout: None,
+                    }),
+                    ..Lifecycle::default()
+                },
+                default_configuration: json!({}),
+            },
+            configuration,
+        };
+        let running = component("cancel-running", json!({ "port": 1 }), None);
+        apply(vec![running], &AtomicBool::new(false)).await.unwrap();
+        let mut changes = CONFIGURATION_CHANGES.subscribe();
+
+        // The configuration stage is done, and the update is canceled before it is applied.
+        let canceled = AtomicBool::new(true);
+        let update = vec![
+            component("cancel-running", json!({ "port": 2 }), None),
+            component("cancel-added", json!({}), Some("exit 1")),
+        ];
+        let result = bootstrap(&update, &canceled).await;
+        assert!(result.unwrap_err().is::<Canceled>());
+        let result = apply(update, &canceled).await;
+        assert!(result.unwrap_err().is::<Canceled>());
+
+        assert_eq!(configuration("cancel-running"), Some(json!({ "port": 1 })));
+        assert!(!SERVICES.contains_key("cancel-added"));
+        while let Ok(change) = changes.try_recv() {
+            assert_ne!(change.component, "cancel-running");
+        }
+
+        apply(vec![], &AtomicBool::new(false)).await.unwrap();
+        assert!(!SERVICES.contains_key("cancel-running"));
+    }
+
+    #[tokio::test]
+    async fn starts_deployed_components_again() {
+        let _lock = TEST_LOCK.lock().await;
+        provisioning::test_configuration();
+        let component = Component {
+            recipe: Recipe::from_json(&json!({
+                "ComponentName": "com.example.Deployed",
+                "ComponentVersion": "1.0.0"
+            }))
+            .unwrap(),
+            configuration: json!({ "port": 1 }),
+        };
+        apply(vec![component], &AtomicBool::new(false))
+            .await
+            .unwrap();
+
+        // The nucleus restarted.
+        COMPONENTS.lock().unwrap().remove("com.example.Deployed");
+        SERVICES.remove("com.example.Deployed");
+        start_deployed().await;
+        assert_eq!(
+            configuration("com.example.Deployed"),
+            Some(json!({ "port": 1 }))
+        );
+        assert!(SERVICES.contains_key("com.example.Deployed"));
+
+        apply(vec![], &AtomicBool::new(false)).await.unwrap();
+        start_deployed().await;
+        assert!(deployed().is_empty());
+    }
+}
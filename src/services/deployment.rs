@@ -1,28 +1,45 @@
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use anyhow::{bail, Context, Ok};
+use anyhow::{anyhow, bail, Context, Ok};
 use anyhow::{Error, Result};
-use aws_config::meta::region::RegionProviderChain;
 use aws_iot_device_sdk::shadow;
 use aws_sdk_greengrassv2::Client as Greengrassv2_Client;
-use aws_sdk_greengrassv2::Region;
 use aws_sdk_s3::Client as S3_Client;
-use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
 use once_cell::sync::Lazy;
-use rumqttc::Publish;
-use rumqttc::{AsyncClient, QoS};
+use rumqttc::QoS;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value;
-use tokio::sync::mpsc::Sender;
 use tokio::time;
+use tracing::{debug, error, info, warn};
 
+use crate::mqtt::{
+    Callback, Message, MqttClient, PublishRequest, SubscribeRequest, UnsubscribeRequest,
+};
+use crate::services::alts::Alternatives;
+use crate::services::component::{self, Component, ConfigurationUpdate, Recipe, Snapshot};
+use crate::services::deployment_groups::{DeploymentGroups, GroupComponents, DEPLOYMENT_GROUPS};
+use crate::services::deployment_queue::DEPLOYMENT_QUEUE;
+use crate::services::policy::{self, ComponentUpdatePolicy};
+use crate::services::status::{self, DeploymentInformation, StatusDetails, Trigger};
+use crate::services::{jobs, kernel};
 use crate::services::{Service, SERVICES};
-use crate::{config, ggcVersion};
+use crate::{config, ggcVersion, provisioning, proxy};
 const VERSION: &str = "0.0.0";
 
+const DEPLOYMENTS_DIRECTORY: &str = "deployments";
+const ONGOING_DEPLOYMENT_FILE: &str = "ongoing.json";
+const DEPLOYMENT_GROUPS_FILE: &str = "groups.json";
+const LOCAL_DEPLOYMENT_GROUP_NAME: &str = "LOCAL_DEPLOYMENT";
+/// How long a restarted nucleus has to bring the components of its deployment up.
+const KERNEL_ACTIVATION_TIMEOUT: Duration = Duration::from_secs(300);
 
 pub const CONFIGURATION_ARN_LOG_KEY_NAME: &str = "CONFIGURATION_ARN";
 pub const DESIRED_STATUS_KEY: &str = "desiredStatus";
@@ -33,6 +50,16 @@ pub const DEPLOYMENT_SHADOW_NAME: &str = "AWSManagedGreengrassV2Deployment";
 pub const DEVICE_OFFLINE_MESSAGE: &str = "Device not configured to talk to AWS Iot cloud. ";
 // + "Single device deployment is offline";
 pub const SUBSCRIBING_TO_SHADOW_TOPICS_MESSAGE: &str = "Subscribing to Iot Shadow topics";
+pub const DETAILED_STATUS_SUCCESSFUL: &str = "SUCCESSFUL";
+pub const DETAILED_STATUS_FAILED_NO_STATE_CHANGE: &str = "FAILED_NO_STATE_CHANGE";
+pub const DETAILED_STATUS_FAILED_ROLLBACK_NOT_REQUESTED: &str = "FAILED_ROLLBACK_NOT_REQUESTED";
+pub const DETAILED_STATUS_FAILED_ROLLBACK_COMPLETE: &str = "FAILED_ROLLBACK_COMPLETE";
+pub const DETAILED_STATUS_FAILED_UNABLE_TO_ROLLBACK: &str = "FAILED_UNABLE_TO_ROLLBACK";
+pub const GROUP_NAME_KEY: &str = "groupName";
+pub const COMPONENT_UPDATE_POLICY_KEY: &str = "componentUpdatePolicy";
+pub const CONFIGURATION_UPDATE_KEY: &str = "configurationUpdate";
+pub const FAILURE_HANDLING_POLICY_KEY: &str = "failureHandlingPolicy";
+pub const FAILURE_HANDLING_POLICY_ROLLBACK: &str = "ROLLBACK";
 
 const NAME: &str = "DeploymentService";
 pub struct Deployments {}
@@ -43,179 +70,934 @@ impl Service for Deployments {
     }
 }
 
-static DEPLOYSTATUS: DeployStates = DeployStates {
-    mutex: Mutex::new(States::Deployment),
-};
+/// Last known state of every deployment seen since the nucleus started, by deployment id.
+static DEPLOYMENTS: Lazy<DashMap<String, DeploymentState>> = Lazy::new(DashMap::new);
+
+/**
+ * The lifecycle of a deployment.
+ *
+ * A deployment is `QUEUED` when it is received, `IN_PROGRESS` while its components are being
+ * deployed and ends up `SUCCEEDED`, `FAILED`, `CANCELED` or `ROLLED_BACK`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DeploymentState {
+    Queued,
+    InProgress,
+    Succeeded,
+    Failed,
+    Canceled,
+    RolledBack,
+}
+
+impl DeploymentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeploymentState::Queued => "QUEUED",
+            DeploymentState::InProgress => "IN_PROGRESS",
+            DeploymentState::Succeeded => "SUCCEEDED",
+            DeploymentState::Failed => "FAILED",
+            DeploymentState::Canceled => "CANCELED",
+            DeploymentState::RolledBack => "ROLLED_BACK",
+        }
+    }
 
-#[derive(Debug, Copy, Clone)]
-enum States {
-    Deployment = 0,
-    Inprogress,
-    Succeed,
-}
-struct DeployStates {
-    mutex: Mutex<States>,
-}
-impl DeployStates {
-    fn get(&self) -> States {
-        let lock = self.mutex.lock().unwrap();
-        *lock
-    }
-    fn reset(&self) {
-        let mut lock = self.mutex.lock().unwrap();
-        *lock = States::Deployment;
-    }
-    fn next(&self) {
-        let mut lock = self.mutex.lock().unwrap();
-        match *lock {
-            States::Deployment => *lock = States::Inprogress,
-            States::Inprogress => *lock = States::Succeed,
-            States::Succeed => self.reset(),
+    /// The status reported to the cloud, where a rolled back deployment is a failed one.
+    pub fn reported_status(&self) -> &'static str {
+        match self {
+            DeploymentState::RolledBack => DeploymentState::Failed.as_str(),
+            _ => self.as_str(),
         }
     }
+
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self, DeploymentState::Queued | DeploymentState::InProgress)
+    }
+
+    fn can_transition_to(&self, next: DeploymentState) -> bool {
+        use DeploymentState::*;
+        matches!(
+            (self, next),
+            (Queued, InProgress)
+                | (Queued, Canceled)
+                | (InProgress, Succeeded)
+                | (InProgress, Failed)
+                | (InProgress, Canceled)
+                | (InProgress, RolledBack)
+        )
+    }
 }
 
-pub async fn connect_shadow(mqtt_client: &AsyncClient, thing_name: &str) -> Result<()> {
-    let topic = format!("$aws/things/{thing_name}/shadow/name/{DEPLOYMENT_SHADOW_NAME}/#");
-    mqtt_client.subscribe(&topic, QoS::AtMostOnce).await?;
-    Ok(())
+/// Where a deployment came from, with what is needed to report its progress back there.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum DeploymentSource {
+    /// The `AWSManagedGreengrassV2Deployment` shadow, for deployments targeting this thing.
+    Shadow {
+        thing_name: String,
+    },
+    /// IoT Jobs, for deployments targeting a thing group.
+    Jobs {
+        thing_name: String,
+        job_id: String,
+        /// Version of the job execution, for the `expectedVersion` of the next update.
+        version: i64,
+        /// Whether the job execution is already `IN_PROGRESS` in the cloud.
+        in_progress: bool,
+    },
+    Local,
 }
 
-pub async fn disconnect_shadow(mqtt_client: AsyncClient, thing_name: &str) -> Result<()> {
-    let topic = format!("$aws/things/{thing_name}/shadow/name/{DEPLOYMENT_SHADOW_NAME}/#");
-    mqtt_client.unsubscribe(&topic).await?;
-    Ok(())
+impl DeploymentSource {
+    fn trigger(&self) -> Trigger {
+        match self {
+            DeploymentSource::Shadow { .. } => Trigger::ThingDeployment,
+            DeploymentSource::Jobs { .. } => Trigger::ThingGroupDeployment,
+            DeploymentSource::Local => Trigger::LocalDeployment,
+        }
+    }
+}
+
+/// A single deployment and where it is in its lifecycle.
+#[derive(Debug)]
+pub struct Deployment {
+    pub id: String,
+    pub configuration_arn: String,
+    pub source: DeploymentSource,
+    pub document: Value,
+    state: DeploymentState,
+    status_details: StatusDetails,
+    canceled: Arc<AtomicBool>,
+    stage: DeploymentStage,
+    /// Components before the deployment, to roll back to.
+    snapshot: Option<Snapshot>,
+    restart: Option<i32>,
 }
 
-fn assemble_payload(thing_name: &str, arn: &str, version: &str) -> Result<Value> {
-    let version: u8 = version.parse()?;
-    // for next status
-    match DEPLOYSTATUS.get() {
-        States::Deployment => Ok(json!({
-          "shadowName": DEPLOYMENT_SHADOW_NAME,
-          "thing_name": thing_name,
-          "state": {
-            "reported": {
-              "ggcVersion": ggcVersion,
-              "fleetConfigurationArnForStatus": arn,
-              "status_details": {},
-              "status": "IN_PROGRESS"
+/// Where an `IN_PROGRESS` deployment is, across the restarts it needs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DeploymentStage {
+    /// Run by the nucleus that received the deployment.
+    #[default]
+    Default,
+    /// Run again by the nucleus restarted for the deployment, which is being activated.
+    KernelActivation,
+    /// The update failed after a restart and the nucleus restarted into its previous version.
+    KernelRollback,
+}
+
+impl Deployment {
+    /// Register a new `QUEUED` deployment, or return `None` if deployment `id` was already seen.
+    pub fn register(id: &str, source: DeploymentSource, document: Value) -> Result<Option<Self>> {
+        let configuration_arn = configuration_arn(&document)?.to_string();
+        match DEPLOYMENTS.entry(id.to_string()) {
+            Entry::Occupied(_) => return Ok(None),
+            Entry::Vacant(entry) => entry.insert(DeploymentState::Queued),
+        };
+        Ok(Some(Deployment {
+            id: id.to_string(),
+            configuration_arn,
+            source,
+            document,
+            state: DeploymentState::Queued,
+            status_details: StatusDetails::default(),
+            canceled: Arc::new(AtomicBool::new(false)),
+            stage: DeploymentStage::Default,
+            snapshot: None,
+            restart: None,
+        }))
+    }
+
+    pub fn state(&self) -> DeploymentState {
+        self.state
+    }
+
+    pub fn status_details(&self) -> &StatusDetails {
+        &self.status_details
+    }
+
+    /// Whether the deployment was canceled while running.
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::SeqCst)
+    }
+
+    /// The flag that cancels this deployment once set.
+    pub(crate) fn cancellation(&self) -> Arc<AtomicBool> {
+        self.canceled.clone()
+    }
+
+    /// Move to `next`, reporting the deployment to the fleet status service once it starts and
+    /// once it is done.
+    pub fn transition(
+        &mut self,
+        next: DeploymentState,
+        status_details: StatusDetails,
+    ) -> Result<()> {
+        if !self.state.can_transition_to(next) {
+            bail!(
+                "Deployment {} cannot go from {} to {}.",
+                self.id,
+                self.state.as_str(),
+                next.as_str()
+            );
+        }
+        info!(
+            "Deployment {} is {}, was {}.",
+            self.id,
+            next.as_str(),
+            self.state.as_str()
+        );
+        self.state = next;
+        self.status_details = status_details;
+        DEPLOYMENTS.insert(self.id.clone(), next);
+        status::deployment_status_changed(self.source.trigger(), self.information());
+        Ok(())
+    }
+
+    /**
+     * Deploy the components of an `IN_PROGRESS` deployment, ending as `SUCCEEDED` or `FAILED`.
+     *
+     * Components subscribed to updates are notified first, according to the
+     * `componentUpdatePolicy` of the deployment, and the components on the device are
+     * snapshotted and kept in the ongoing deployment file at `ongoing` before any of them
+     * changes; a deployment resumed after the nucleus stopped reuses that snapshot. When
+     * applying the new components fails and the deployment asks for `ROLLBACK`, the snapshot is
+     * restored and the deployment ends as `ROLLED_BACK`. A deployment canceled before components
+     * change ends as `CANCELED`.
+     *
+     * Bootstrap steps and nucleus updates need a restart: the deployment then stays
+     * `IN_PROGRESS` with `restart_requested` set, and the restarted nucleus executes it again in
+     * the `KERNEL_ACTIVATION` stage.
+     */
+    pub async fn execute(&mut self, ongoing: &Path) -> Result<()> {
+        if self.stage == DeploymentStage::KernelRollback {
+            return self.complete_rollback().await;
+        }
+        let components = match resolve(&self.document).await {
+            std::result::Result::Ok(components) => components,
+            Err(e) if self.stage == DeploymentStage::Default => {
+                error!("Deployment {} failed: {:#}", self.id, e);
+                return self.fail(
+                    DeploymentState::Failed,
+                    DETAILED_STATUS_FAILED_NO_STATE_CHANGE,
+                    e,
+                );
+            }
+            Err(e) => return self.handle_failure(&[], e).await,
+        };
+        // The nucleus is updated through the alternatives, not as a component.
+        let (nucleus, components): (Vec<Component>, Vec<Component>) = components
+            .into_iter()
+            .partition(|component| component.recipe.name == kernel::NAME);
+        let nucleus = nucleus.into_iter().next();
+
+        if self.stage == DeploymentStage::Default {
+            let policy: ComponentUpdatePolicy = match self.document.get(COMPONENT_UPDATE_POLICY_KEY)
+            {
+                None | Some(Value::Null) => ComponentUpdatePolicy::default(),
+                Some(policy) => serde_json::from_value(policy.clone()).unwrap_or_else(|e| {
+                    warn!("Invalid component update policy, using the default: {}", e);
+                    ComponentUpdatePolicy::default()
+                }),
+            };
+            policy::wait_for_safe_update(&self.id, &policy).await;
+            if self.snapshot.is_none() {
+                self.snapshot = Some(component::snapshot());
+                persist(self, ongoing);
+            }
+            match self.bootstrap(&components, nucleus.as_ref()).await {
+                std::result::Result::Ok(Some(code)) => {
+                    info!("Deployment {} needs a restart.", self.id);
+                    self.stage = DeploymentStage::KernelActivation;
+                    self.restart = Some(code);
+                    return Ok(());
+                }
+                std::result::Result::Ok(None) => {}
+                Err(e) => {
+                    policy::component_update_done(&self.id);
+                    if let Err(e) = alternatives().discard_new() {
+                        warn!("Failed to discard the prepared nucleus: {:#}", e);
+                    }
+                    if e.is::<component::Canceled>() {
+                        info!("Deployment {} was canceled.", self.id);
+                        return self
+                            .transition(DeploymentState::Canceled, StatusDetails::default());
+                    }
+                    return self.handle_failure(&components, e).await;
+                }
+            }
+        }
+
+        let applied = match self.stage {
+            DeploymentStage::KernelActivation => time::timeout(
+                KERNEL_ACTIVATION_TIMEOUT,
+                component::apply(components.clone(), &self.canceled),
+            )
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("The nucleus did not come up in time.")))
+            .and_then(|()| match &nucleus {
+                Some(nucleus) if nucleus.recipe.version != kernel::version() => bail!(
+                    "Nucleus {} was requested but {} is running.",
+                    nucleus.recipe.version,
+                    kernel::version()
+                ),
+                _ => Ok(()),
+            }),
+            _ => component::apply(components.clone(), &self.canceled).await,
+        };
+        policy::component_update_done(&self.id);
+        match applied {
+            std::result::Result::Ok(()) => {
+                if let Err(e) = alternatives().activation_succeeded() {
+                    warn!("Failed to clean up the previous nucleus: {:#}", e);
+                }
+                if let Err(e) = self.record_group() {
+                    warn!(
+                        "Failed to record the components of deployment {}: {:#}",
+                        self.id, e
+                    );
+                }
+                let details = StatusDetails {
+                    detailed_status: Some(DETAILED_STATUS_SUCCESSFUL.to_string()),
+                    failure_cause: None,
+                };
+                self.transition(DeploymentState::Succeeded, details)
+            }
+            Err(e) if e.is::<component::Canceled>() => {
+                info!("Deployment {} was canceled.", self.id);
+                self.transition(DeploymentState::Canceled, StatusDetails::default())
             }
-          },
-          "version": version
-        })),
-        States::Inprogress => Ok(json!({
-          "shadowName": DEPLOYMENT_SHADOW_NAME,
-          "thing_name": thing_name,
-          "state": {
-            "reported": {
-              "ggcVersion": ggcVersion,
-              "fleetConfigurationArnForStatus": arn,
-              "status_details": {
-                    "detailedStatus": "SUCCESSFUL"
-              },
-              "status": "SUCCEEDED"
+            Err(e) => self.handle_failure(&components, e).await,
+        }
+    }
+
+    /// Keep the root components of this deployment as the ones of its group.
+    fn record_group(&self) -> Result<()> {
+        let components = GroupComponents {
+            configuration_arn: self.configuration_arn.clone(),
+            components: versions(&components(&self.document)?)?,
+        };
+        let mut groups = DEPLOYMENT_GROUPS.lock().unwrap();
+        groups.set(&group_name(&self.document), components);
+        groups.save(&deployments_directory().join(DEPLOYMENT_GROUPS_FILE))
+    }
+
+    /// The exit code to restart the nucleus with before this deployment can go on.
+    pub fn restart_requested(&self) -> Option<i32> {
+        self.restart
+    }
+
+    /// Run the bootstrap steps and prepare the nucleus update, returning the restart they need.
+    async fn bootstrap(
+        &self,
+        components: &[Component],
+        nucleus: Option<&Component>,
+    ) -> Result<Option<i32>> {
+        let mut restart = component::bootstrap(components, &self.canceled).await?;
+        if let Some(nucleus) = nucleus.filter(|n| n.recipe.version != kernel::version()) {
+            if self.is_canceled() {
+                bail!(component::Canceled);
             }
-          },
-          "version": version
-        })),
-        _ => Ok(json!("")),
+            let distro = component::artifacts_path(kernel::NAME, &nucleus.recipe.version);
+            alternatives()
+                .prepare(&self.id, &distro)
+                .context("Failed to prepare the nucleus update.")?;
+            restart = restart.max(Some(kernel::REQUEST_RESTART));
+        }
+        Ok(restart)
+    }
+
+    /// Roll back after the update of `components` failed with `e`, if the deployment asks for it.
+    async fn handle_failure(&mut self, components: &[Component], e: Error) -> Result<()> {
+        error!("Deployment {} failed: {:#}", self.id, e);
+        let alternatives = alternatives();
+        if self.document[FAILURE_HANDLING_POLICY_KEY] != FAILURE_HANDLING_POLICY_ROLLBACK {
+            if let Err(e) = alternatives.activation_succeeded() {
+                warn!("Failed to clean up the previous nucleus: {:#}", e);
+            }
+            return self.fail(
+                DeploymentState::Failed,
+                DETAILED_STATUS_FAILED_ROLLBACK_NOT_REQUESTED,
+                e,
+            );
+        }
+        if self.stage == DeploymentStage::KernelActivation && alternatives.is_activating() {
+            match alternatives.prepare_rollback() {
+                std::result::Result::Ok(()) => {
+                    self.stage = DeploymentStage::KernelRollback;
+                    self.status_details.failure_cause = Some(format!("{e:#}"));
+                    self.restart = Some(kernel::REQUEST_RESTART);
+                    return Ok(());
+                }
+                Err(rollback) => {
+                    error!("Failed to roll back deployment {}: {:#}", self.id, rollback);
+                    return self.fail(
+                        DeploymentState::Failed,
+                        DETAILED_STATUS_FAILED_UNABLE_TO_ROLLBACK,
+                        e,
+                    );
+                }
+            }
+        }
+        info!("Rolling back deployment {}.", self.id);
+        let snapshot = self.snapshot.take().unwrap_or_default();
+        match snapshot.restore(components).await {
+            std::result::Result::Ok(()) => self.fail(
+                DeploymentState::RolledBack,
+                DETAILED_STATUS_FAILED_ROLLBACK_COMPLETE,
+                e,
+            ),
+            Err(rollback) => {
+                error!("Failed to roll back deployment {}: {:#}", self.id, rollback);
+                self.fail(
+                    DeploymentState::Failed,
+                    DETAILED_STATUS_FAILED_UNABLE_TO_ROLLBACK,
+                    e,
+                )
+            }
+        }
+    }
+
+    /// Fail the deployment when the nucleus update it prepared could not be activated.
+    async fn activation_failed(&mut self, e: Error) -> Result<()> {
+        let alternatives = alternatives();
+        let restored = alternatives.discard_new().and_then(|()| {
+            if alternatives.is_activating() {
+                alternatives.prepare_rollback()?;
+                alternatives.rollback_completed()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = restored {
+            warn!("Failed to restore the running nucleus: {:#}", e);
+        }
+        self.stage = DeploymentStage::Default;
+        self.restart = None;
+        self.handle_failure(&[], e.context("Failed to activate the nucleus update"))
+            .await
+    }
+
+    /// Bring the components back once restarted into the previous nucleus.
+    async fn complete_rollback(&mut self) -> Result<()> {
+        let cause = self.status_details.failure_cause.clone();
+        let snapshot = self.snapshot.take().unwrap_or_default();
+        let detailed_status = match snapshot.restore(&[]).await {
+            std::result::Result::Ok(()) => {
+                if let Err(e) = alternatives().rollback_completed() {
+                    warn!("Failed to clean up the broken nucleus: {:#}", e);
+                }
+                DETAILED_STATUS_FAILED_ROLLBACK_COMPLETE
+            }
+            Err(e) => {
+                error!("Failed to roll back deployment {}: {:#}", self.id, e);
+                DETAILED_STATUS_FAILED_UNABLE_TO_ROLLBACK
+            }
+        };
+        let next = if detailed_status == DETAILED_STATUS_FAILED_ROLLBACK_COMPLETE {
+            DeploymentState::RolledBack
+        } else {
+            DeploymentState::Failed
+        };
+        let details = StatusDetails {
+            detailed_status: Some(detailed_status.to_string()),
+            failure_cause: cause,
+        };
+        self.transition(next, details)
+    }
+
+    fn fail(&mut self, next: DeploymentState, detailed_status: &str, cause: Error) -> Result<()> {
+        let details = StatusDetails {
+            detailed_status: Some(detailed_status.to_string()),
+            failure_cause: Some(format!("{cause:#}")),
+        };
+        self.transition(next, details)
+    }
+
+    pub fn information(&self) -> DeploymentInformation {
+        DeploymentInformation {
+            status: self.state.reported_status().to_string(),
+            status_details: self.status_details.clone(),
+            fleetConfigurationArnForStatus: self.configuration_arn.clone(),
+        }
     }
 }
 
-fn assemble_publish_content(v: Value) -> Result<Publish> {
-    let shadow_version = v["version"].to_string();
-    let v: Value = serde_json::from_str(v["state"]["fleetConfig"].as_str().unwrap())?;
-    // "arn:aws:greengrass:<region>:<id>:configuration:thing/<name>:<version>"
-    let configuration_arn = v["configurationArn"]
-        .as_str()
-        .context("Failed to get configuration arn.")?;
-    let (other, version) = configuration_arn
-        .rsplit_once(':')
-        .context("Failed to get configuration version.")?;
-    let (_, thing_name) = other
-        .rsplit_once('/')
-        .context("Failed to get thing name.")?;
+/// Queue `deployment` for execution, canceling the deployment it makes obsolete, if any.
+pub async fn enqueue(mqtt_client: &MqttClient, deployment: Deployment) -> Result<()> {
+    if let Some(dropped) = DEPLOYMENT_QUEUE.offer(deployment) {
+        cancel_queued(mqtt_client, dropped).await?;
+    }
+    Ok(())
+}
+
+/// Cancel the queued and running deployments whose source matches `canceled`.
+pub async fn cancel(
+    mqtt_client: &MqttClient,
+    canceled: impl Fn(&DeploymentSource) -> bool,
+) -> Result<()> {
+    for deployment in DEPLOYMENT_QUEUE.cancel(canceled) {
+        cancel_queued(mqtt_client, deployment).await?;
+    }
+    Ok(())
+}
+
+async fn cancel_queued(mqtt_client: &MqttClient, mut deployment: Deployment) -> Result<()> {
+    deployment.transition(DeploymentState::Canceled, StatusDetails::default())?;
+    report(mqtt_client, &mut deployment).await;
+    Ok(())
+}
+
+/// Execute queued deployments one at a time, starting with the one interrupted by a restart.
+pub async fn process_deployments(mqtt_client: MqttClient) {
+    let directory = deployments_directory();
+    let path = directory.join(ONGOING_DEPLOYMENT_FILE);
+    *DEPLOYMENT_GROUPS.lock().unwrap() =
+        DeploymentGroups::load(&directory.join(DEPLOYMENT_GROUPS_FILE));
+    update_fleet_config_arns();
+    if let Err(e) = resume(&mqtt_client, &path).await {
+        error!("Failed to resume the interrupted deployment: {:#}", e);
+    }
+    loop {
+        let mut deployment = DEPLOYMENT_QUEUE.next().await;
+        if let Err(e) = run(&mqtt_client, &mut deployment, &path).await {
+            error!("Failed to process deployment {}: {:#}", deployment.id, e);
+        }
+        DEPLOYMENT_QUEUE.done();
+    }
+}
+
+/// Execute `deployment`, keeping it in the ongoing deployment file at `path` until its outcome
+/// is reported.
+async fn run(mqtt_client: &MqttClient, deployment: &mut Deployment, path: &Path) -> Result<()> {
+    deployment.transition(DeploymentState::InProgress, StatusDetails::default())?;
+    report(mqtt_client, deployment).await;
+    // After reporting, which moves the version of a job execution on.
+    persist(deployment, path);
+    deployment.execute(path).await?;
+    persist(deployment, path);
+    if let Some(code) = prepare_restart(deployment, path).await? {
+        // The restarted nucleus picks the deployment up from the ongoing deployment file.
+        kernel::restart(code);
+    }
+    update_fleet_config_arns();
+    if !report(mqtt_client, deployment).await {
+        // Reported again on the next start.
+        return Ok(());
+    }
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to remove {}: {}", path.display(), e);
+    }
+    Ok(())
+}
+
+/**
+ * Get ready for the restart `deployment` asks for, returning the exit code to restart with.
+ *
+ * The prepared nucleus update is activated, then every component is stopped so that none
+ * outlives this nucleus and runs twice once the restarted one starts them. A failed activation
+ * fails the deployment instead, with no restart.
+ */
+async fn prepare_restart(deployment: &mut Deployment, path: &Path) -> Result<Option<i32>> {
+    let Some(code) = deployment.restart_requested() else {
+        return Ok(None);
+    };
+    let alternatives = alternatives();
+    let activated = if alternatives.is_prepared() {
+        alternatives.activate()
+    } else {
+        Ok(())
+    };
+    if let Err(e) = activated {
+        deployment.activation_failed(e).await?;
+        persist(deployment, path);
+        return Ok(None);
+    }
+    component::stop_all().await;
+    Ok(Some(code))
+}
+
+/// What is kept of the running deployment, to pick it up after a restart.
+#[derive(Serialize, Deserialize, Debug)]
+struct OngoingDeployment {
+    id: String,
+    source: DeploymentSource,
+    document: Value,
+    state: DeploymentState,
+    #[serde(rename = "statusDetails")]
+    status_details: StatusDetails,
+    #[serde(default)]
+    stage: DeploymentStage,
+    #[serde(default)]
+    snapshot: Option<Snapshot>,
+}
+
+fn persist(deployment: &Deployment, path: &Path) {
+    let ongoing = OngoingDeployment {
+        id: deployment.id.clone(),
+        source: deployment.source.clone(),
+        document: deployment.document.clone(),
+        state: deployment.state,
+        status_details: deployment.status_details.clone(),
+        stage: deployment.stage,
+        snapshot: deployment.snapshot.clone(),
+    };
+    // Write then rename, so that a crash never leaves a partial file behind.
+    let temporary = path.with_extension("tmp");
+    let result = path
+        .parent()
+        .map_or(std::result::Result::Ok(()), fs::create_dir_all)
+        .map_err(Error::from)
+        .and_then(|()| Ok(fs::write(&temporary, serde_json::to_vec(&ongoing)?)?))
+        .and_then(|()| Ok(fs::rename(&temporary, path)?));
+    if let Err(e) = result {
+        warn!("Failed to persist deployment {}: {:#}", deployment.id, e);
+    }
+}
+
+/**
+ * Pick up the deployment the nucleus was running when it stopped.
+ *
+ * A deployment that was still `IN_PROGRESS` is queued again to be executed from its stage,
+ * since its components are no longer running. One that had finished only has its outcome
+ * reported.
+ */
+async fn resume(mqtt_client: &MqttClient, path: &Path) -> Result<()> {
+    let data = match fs::read(path) {
+        std::result::Result::Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let ongoing: OngoingDeployment =
+        serde_json::from_slice(&data).context("Failed to deserialize the ongoing deployment.")?;
+    let Some(mut deployment) = restore(ongoing)? else {
+        return Ok(());
+    };
+    if !deployment.state.is_terminal() {
+        return enqueue(mqtt_client, deployment).await;
+    }
+    if report(mqtt_client, &mut deployment).await {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
 
+/// Register the deployment kept in `ongoing` again, as it was when the nucleus stopped.
+fn restore(ongoing: OngoingDeployment) -> Result<Option<Deployment>> {
+    let Some(mut deployment) = Deployment::register(&ongoing.id, ongoing.source, ongoing.document)?
+    else {
+        debug!("Deployment {} was received again.", ongoing.id);
+        return Ok(None);
+    };
+    if !ongoing.state.is_terminal() {
+        info!(
+            "Resuming interrupted deployment {} at stage {:?}.",
+            deployment.id, ongoing.stage
+        );
+        // Queued again, to be executed from its stage with its snapshot.
+        deployment.stage = ongoing.stage;
+        deployment.snapshot = ongoing.snapshot;
+        deployment.status_details = ongoing.status_details;
+        if deployment.stage == DeploymentStage::KernelActivation && alternatives().is_rolling_back()
+        {
+            // The new nucleus never came up and was rolled back when launching this one.
+            deployment.stage = DeploymentStage::KernelRollback;
+            deployment.status_details.failure_cause =
+                Some("The new nucleus failed to launch.".to_string());
+        }
+        return Ok(Some(deployment));
+    }
+    info!(
+        "Reporting deployment {} interrupted after it was {}.",
+        deployment.id,
+        ongoing.state.as_str()
+    );
+    deployment.transition(DeploymentState::InProgress, StatusDetails::default())?;
+    deployment.transition(ongoing.state, ongoing.status_details)?;
+    Ok(Some(deployment))
+}
+
+/// Report the state of `deployment` back to its source, returning whether the report was
+/// accepted.
+async fn report(mqtt_client: &MqttClient, deployment: &mut Deployment) -> bool {
+    let result = match &deployment.source {
+        DeploymentSource::Shadow { thing_name } => {
+            let thing_name = thing_name.clone();
+            report_shadow(mqtt_client, &thing_name, deployment).await
+        }
+        DeploymentSource::Jobs { .. } => jobs::report(mqtt_client, deployment).await,
+        DeploymentSource::Local => Ok(()),
+    };
+    if let Err(e) = &result {
+        warn!(
+            "Failed to report deployment {} as {}: {:#}",
+            deployment.id,
+            deployment.state().as_str(),
+            e
+        );
+    }
+    result.is_ok()
+}
+
+/// Last known state of deployment `id`.
+pub fn deployment_state(id: &str) -> Option<DeploymentState> {
+    DEPLOYMENTS.get(id).map(|state| *state)
+}
+
+/// Subscribe to deployment shadow deltas, returning the callback to pass to `disconnect_shadow`.
+pub async fn connect_shadow(mqtt_client: &MqttClient, thing_name: &str) -> Result<Callback> {
+    let client = mqtt_client.clone();
+    let name = thing_name.to_string();
+    let callback: Callback = Arc::new(move |message: &Message| {
+        let client = client.clone();
+        let message = message.clone();
+        let thing_name = name.clone();
+        tokio::spawn(async move {
+            if let Err(e) = shadow_deployment(message, client, &thing_name).await {
+                error!("Failed to process shadow deployment: {:#}", e);
+            }
+        });
+    });
+    mqtt_client
+        .subscribe(SubscribeRequest {
+            topic: delta_topic(thing_name)?,
+            qos: QoS::AtMostOnce,
+            callback: callback.clone(),
+        })
+        .await?;
+    Ok(callback)
+}
+
+pub async fn disconnect_shadow(
+    mqtt_client: &MqttClient,
+    thing_name: &str,
+    callback: Callback,
+) -> Result<()> {
+    mqtt_client
+        .unsubscribe(UnsubscribeRequest {
+            topic: delta_topic(thing_name)?,
+            callback,
+        })
+        .await
+}
+
+fn delta_topic(thing_name: &str) -> Result<String> {
     let topic = shadow::assemble_topic(
-        shadow::Topic::Update,
+        shadow::Topic::UpdateDelta,
         thing_name,
         Some(DEPLOYMENT_SHADOW_NAME),
     )
     .map_err(Error::msg)?;
-    let payload = assemble_payload(thing_name, configuration_arn, &shadow_version)?;
-    Ok(Publish {
-        dup: false,
-        qos: QoS::AtMostOnce,
-        retain: false,
-        pkid: 0,
-        topic: topic.to_string(),
-        payload: Bytes::from(payload.to_string()),
+    Ok(topic.to_string())
+}
+
+fn assemble_payload(thing_name: &str, deployment: &Deployment) -> Value {
+    json!({
+      "shadowName": DEPLOYMENT_SHADOW_NAME,
+      "thing_name": thing_name,
+      "state": {
+        "reported": {
+          "ggcVersion": ggcVersion,
+          "fleetConfigurationArnForStatus": deployment.configuration_arn,
+          "status_details": deployment.status_details(),
+          "status": deployment.state().reported_status()
+        }
+      }
     })
 }
 
-pub async fn shadow_deployment(v: Publish, tx: Sender<Publish>) -> Result<()> {
+/// Report the state of `deployment` in the deployment shadow.
+async fn report_shadow(
+    mqtt_client: &MqttClient,
+    thing_name: &str,
+    deployment: &Deployment,
+) -> Result<()> {
+    let topic = shadow::assemble_topic(
+        shadow::Topic::Update,
+        thing_name,
+        Some(DEPLOYMENT_SHADOW_NAME),
+    )
+    .map_err(Error::msg)?;
+    let payload = assemble_payload(thing_name, deployment);
+    mqtt_client
+        .publish(PublishRequest::new(topic.as_str(), payload.to_string()).qos(QoS::AtLeastOnce))
+        .await?;
+    Ok(())
+}
+
+// "arn:aws:greengrass:<region>:<id>:configuration:thing/<name>:<version>"
+pub(crate) fn configuration_arn(fleet_config: &Value) -> Result<&str> {
+    fleet_config["configurationArn"]
+        .as_str()
+        .context("Failed to get configuration arn.")
+}
+
+/**
+ * Handle a delta of the deployment shadow.
+ *
+ * The delta repeats the desired fleet configuration until the reported state catches up, so a
+ * configuration that was already seen is ignored.
+ */
+pub async fn shadow_deployment(
+    v: Message,
+    mqtt_client: MqttClient,
+    thing_name: &str,
+) -> Result<()> {
     let v: Value = serde_json::from_slice(&v.payload)
         .context("Failed to deserialize deployment json file.")?;
-    match DEPLOYSTATUS.get() {
-        States::Deployment => {
-            let value = assemble_publish_content(v)?;
-            tx.send(value).await;
-        }
-        States::Inprogress => {
-            let data: Value = serde_json::from_str(v["state"]["fleetConfig"].as_str().unwrap())?;
-
-            // println!("[components] is {}", data["components"]);
-            let mut map: HashMap<String, HashMap<String, serde_json::Value>> =
-                serde_json::from_value(data["components"].to_owned())?;
-            for (k, v) in map.drain().take(1) {
-                component_deploy(
-                    k,
-                    v.get("version")
-                        .context("Failed to find version feild.")?
-                        .to_string()
-                        .trim_matches('"')
-                        .to_string(),
-                )
-                .await;
-            }
-
-            let value = assemble_publish_content(v)?;
-            tx.send(value).await;
+    if v["state"][DESIRED_STATUS_KEY] == DESIRED_STATUS_CANCELED {
+        info!("Shadow deployment canceled.");
+        return cancel(&mqtt_client, |source| {
+            matches!(source, DeploymentSource::Shadow { .. })
+        })
+        .await;
+    }
+    let fleet_config = v["state"]["fleetConfig"]
+        .as_str()
+        .context("Shadow delta has no fleet configuration.")?;
+    let fleet_config: Value = serde_json::from_str(fleet_config)?;
+    let arn = configuration_arn(&fleet_config)?.to_string();
+    let source = DeploymentSource::Shadow {
+        thing_name: thing_name.to_string(),
+    };
+    match Deployment::register(&arn, source, fleet_config)? {
+        Some(deployment) => enqueue(&mqtt_client, deployment).await,
+        None => {
+            debug!("Deployment {} was already received.", arn);
+            Ok(())
         }
-        States::Succeed => {}
     }
-    DEPLOYSTATUS.next();
-    Ok(())
 }
 
-async fn component_deploy(name: String, version: String) -> Result<()> {
+fn deployments_directory() -> PathBuf {
+    provisioning::SystemConfiguration::global()
+        .rootpath
+        .join(DEPLOYMENTS_DIRECTORY)
+}
+
+fn alternatives() -> Alternatives {
+    Alternatives::new(&provisioning::SystemConfiguration::global().rootpath)
+}
+
+/// Fetch the recipes and artifacts of every component of a fleet configuration, whether it came
+/// from the deployment shadow or from an IoT job, and work out their configuration, without
+/// changing the components on the device.
+async fn resolve(fleet_config: &Value) -> Result<Vec<Component>> {
+    let components = components(fleet_config)?;
+    // Along with the root components of the other groups.
+    let versions = DEPLOYMENT_GROUPS
+        .lock()
+        .unwrap()
+        .merge(&group_name(fleet_config), &versions(&components)?)?;
     let region = config::Config::global()
         .services
         .kernel
         .configuration
         .region
         .as_str();
-    let region_provider = RegionProviderChain::first_try(Region::new(region)).or_default_provider();
-    let shared_config = aws_config::from_env().region(region_provider).load().await;
+    let shared_config = proxy::sdk_config(region).await?;
     let ggv2_client = Greengrassv2_Client::new(&shared_config);
     let s3_client = S3_Client::new(&shared_config);
 
+    let mut resolved = vec![];
+    for (name, version) in versions {
+        let recipe = resolve_component(&ggv2_client, &s3_client, &name, &version)
+            .await
+            .with_context(|| format!("Failed to resolve component {name} {version}."))?;
+        let update = components
+            .get(&name)
+            .and_then(|component| component.get(CONFIGURATION_UPDATE_KEY));
+        let update: ConfigurationUpdate = match update {
+            None | Some(Value::Null) => ConfigurationUpdate::default(),
+            Some(update) => serde_json::from_value(update.to_owned())
+                .with_context(|| format!("Invalid configuration update for {name}."))?,
+        };
+        let current = component::configuration(&name).unwrap_or_else(|| json!({}));
+        let configuration = update
+            .apply(&current, &recipe.default_configuration)
+            .with_context(|| format!("Failed to update the configuration of {name}."))?;
+        resolved.push(Component {
+            recipe,
+            configuration,
+        });
+    }
+    Ok(resolved)
+}
+
+/// The root components of a fleet configuration.
+fn components(fleet_config: &Value) -> Result<BTreeMap<String, Value>> {
+    match fleet_config.get("components") {
+        None | Some(Value::Null) => Ok(BTreeMap::new()),
+        Some(components) => serde_json::from_value(components.to_owned())
+            .context("Failed to deserialize components."),
+    }
+}
+
+fn versions(components: &BTreeMap<String, Value>) -> Result<BTreeMap<String, String>> {
+    components
+        .iter()
+        .map(|(name, component)| {
+            let version = component["version"]
+                .as_str()
+                .with_context(|| format!("Component {name} has no version."))?;
+            Ok((name.clone(), version.to_string()))
+        })
+        .collect()
+}
+
+/// The group a fleet configuration targets, such as `thinggroup/<name>` or `thing/<name>`.
+fn group_name(fleet_config: &Value) -> String {
+    if let Some(group) = fleet_config[GROUP_NAME_KEY].as_str() {
+        return group.to_string();
+    }
+    // "arn:aws:greengrass:<region>:<id>:configuration:thinggroup/<name>:<revision>"
+    configuration_arn(fleet_config)
+        .ok()
+        .and_then(|arn| arn.split_once(":configuration:"))
+        .map(|(_, target)| target.rsplit_once(':').map_or(target, |(target, _)| target))
+        .unwrap_or(LOCAL_DEPLOYMENT_GROUP_NAME)
+        .to_string()
+}
+
+/// Let every service know which fleet configurations deployed it.
+fn update_fleet_config_arns() {
+    let groups = DEPLOYMENT_GROUPS.lock().unwrap();
+    for mut service in SERVICES.iter_mut() {
+        let arns = groups.fleet_config_arns(service.key());
+        service.set_fleet_config_arns(arns);
+    }
+}
+
+/// Fetch the recipe of a component version and download its artifacts.
+async fn resolve_component(
+    ggv2_client: &Greengrassv2_Client,
+    s3_client: &S3_Client,
+    name: &str,
+    version: &str,
+) -> Result<Recipe> {
     // 1. list-components
-    let arn = list_components(&ggv2_client, &name).await?;
+    let arn = list_components(ggv2_client, name).await?;
     // 1.1. list-component-version
-    let arn = list_component_version(&ggv2_client, &arn, &version).await?;
+    let arn = list_component_version(ggv2_client, &arn, version).await?;
     // 2. get-component to get recipe.
-    let recipe = get_component(&ggv2_client, &arn).await?;
+    let recipe = Recipe::from_json(&get_component(ggv2_client, &arn).await?)?;
     // 3. get-s3 for private component.
-    println!("{}", recipe["Manifests"][0]["Artifacts"][0]["Uri"]);
-    let uri = recipe["Manifests"][0]["Artifacts"][0]["Uri"]
-        .as_str()
-        .unwrap();
-    get_s3_object(&s3_client, &uri).await;
-
-    Ok(())
+    let path = component::artifacts_path(name, version);
+    std::fs::create_dir_all(&path)?;
+    for uri in &recipe.artifacts {
+        if !uri.starts_with("s3://") {
+            bail!("Unsupported artifact URI {}.", uri);
+        }
+        let file = path.join(uri.rsplit('/').next().unwrap_or_default());
+        if file.exists() {
+            debug!("Artifact {} is already downloaded.", uri);
+            continue;
+        }
+        get_s3_object(s3_client, uri, &file).await?;
+    }
+    Ok(recipe)
 }
+
 async fn list_components(client: &Greengrassv2_Client, name: &str) -> Result<String, Error> {
     let resp = client.list_components().send().await?;
 
@@ -261,19 +1043,328 @@ async fn get_component(client: &Greengrassv2_Client, arn: &str) -> Result<Value,
 
     Ok(recipe)
 }
-async fn get_s3_object(client: &S3_Client, uri: &str) -> Result<(), Error> {
+async fn get_s3_object(client: &S3_Client, uri: &str, file: &Path) -> Result<(), Error> {
     let v: Vec<&str> = uri.splitn(4, '/').collect();
-
-    // println!("{:?}", v);
-    // println!("{:?}", v[0]);
-    // println!("{:?}", v[3]);
+    if v.len() < 4 {
+        bail!("Invalid S3 URI {}.", uri);
+    }
 
     let resp = client.get_object().bucket(v[2]).key(v[3]).send().await?;
-    let data = resp.body.collect().await;
-    println!(
-        "Data from downloaded object: {:?}",
-        data // data.unwrap().into_bytes().slice(0..20)
-    );
+    let data = resp.body.collect().await?.into_bytes();
+    tokio::fs::write(file, data)
+        .await
+        .with_context(|| format!("Failed to write artifact {}.", file.display()))?;
+    debug!("Downloaded {} to {}.", uri, file.display());
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deployment_state_machine() {
+        let document = json!({ "configurationArn": "arn:configuration:thing/test:1" });
+        let mut deployment = Deployment::register(
+            "state-machine-test",
+            DeploymentSource::Local,
+            document.clone(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(
+            Deployment::register("state-machine-test", DeploymentSource::Local, document)
+                .unwrap()
+                .is_none()
+        );
+        assert!(deployment
+            .transition(DeploymentState::Succeeded, StatusDetails::default())
+            .is_err());
+        deployment
+            .transition(DeploymentState::InProgress, StatusDetails::default())
+            .unwrap();
+        deployment
+            .transition(DeploymentState::Failed, StatusDetails::default())
+            .unwrap();
+        assert_eq!(
+            deployment_state("state-machine-test"),
+            Some(DeploymentState::Failed)
+        );
+        assert!(deployment.state().is_terminal());
+        assert!(deployment
+            .transition(DeploymentState::InProgress, StatusDetails::default())
+            .is_err());
+        assert_eq!(DeploymentState::RolledBack.reported_status(), "FAILED");
+    }
+
+    #[test]
+    fn persists_ongoing_deployment() {
+        let source = DeploymentSource::Jobs {
+            thing_name: "thing".to_string(),
+            job_id: "persist-test".to_string(),
+            version: 2,
+            in_progress: true,
+        };
+        let document = json!({ "configurationArn": "arn:configuration:thinggroup/test:1" });
+        let mut deployment = Deployment::register("persist-test", source.clone(), document)
+            .unwrap()
+            .unwrap();
+        deployment
+            .transition(DeploymentState::InProgress, StatusDetails::default())
+            .unwrap();
+        let path = std::env::temp_dir()
+            .join(format!("persist-test-{}", std::process::id()))
+            .join(ONGOING_DEPLOYMENT_FILE);
+        persist(&deployment, &path);
+
+        let ongoing: OngoingDeployment = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(ongoing.id, "persist-test");
+        assert_eq!(ongoing.source, source);
+        assert_eq!(ongoing.state, DeploymentState::InProgress);
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn component(name: &str, version: &str, configuration: Value) -> Component {
+        Component {
+            recipe: Recipe {
+                name: name.to_string(),
+                version: version.to_string(),
+                dependencies: vec![],
+                artifacts: vec![],
+                lifecycle: component::Lifecycle::default(),
+                default_configuration: json!({}),
+            },
+            configuration,
+        }
+    }
+
+    fn versions_of(names: &[&str]) -> Vec<(String, String, Value)> {
+        names
+            .iter()
+            .filter_map(|name| {
+                let version = SERVICES.get(*name)?.version.clone();
+                Some((name.to_string(), version, component::configuration(name)?))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rolls_back_failed_deployments() {
+        let _lock = component::TEST_LOCK.lock().await;
+        provisioning::test_configuration();
+        let names = ["rollback-kept", "rollback-updated", "rollback-added"];
+        let before = vec![
+            component("rollback-kept", "1.0.0", json!({})),
+            component("rollback-updated", "1.0.0", json!({ "level": "INFO" })),
+        ];
+        component::apply(before, &AtomicBool::new(false))
+            .await
+            .unwrap();
+        let snapshot = component::snapshot();
+        let expected = versions_of(&names);
+        assert_eq!(expected.len(), 2);
+
+        let attempted = vec![
+            component("rollback-updated", "2.0.0", json!({ "level": "DEBUG" })),
+            component("rollback-added", "1.0.0", json!({})),
+        ];
+        component::apply(attempted.clone(), &AtomicBool::new(false))
+            .await
+            .unwrap();
+        assert_ne!(versions_of(&names), expected);
+
+        let deployment = |id: &str, document: Value| {
+            let mut deployment = Deployment::register(id, DeploymentSource::Local, document)
+                .unwrap()
+                .unwrap();
+            deployment
+                .transition(DeploymentState::InProgress, StatusDetails::default())
+                .unwrap();
+            deployment.snapshot = Some(snapshot.clone());
+            deployment
+        };
+
+        // Without a ROLLBACK policy the failed components are left as they are.
+        let mut failed = deployment(
+            "rollback-not-requested",
+            json!({ "configurationArn": "arn:configuration:thing/rollback:1" }),
+        );
+        failed
+            .handle_failure(&attempted, anyhow!("Component broke."))
+            .await
+            .unwrap();
+        assert_eq!(failed.state(), DeploymentState::Failed);
+        assert_eq!(
+            failed.status_details().detailed_status.as_deref(),
+            Some(DETAILED_STATUS_FAILED_ROLLBACK_NOT_REQUESTED)
+        );
+        assert_ne!(versions_of(&names), expected);
+
+        let mut rolled_back = deployment(
+            "rollback-complete",
+            json!({
+                "configurationArn": "arn:configuration:thing/rollback:2",
+                FAILURE_HANDLING_POLICY_KEY: FAILURE_HANDLING_POLICY_ROLLBACK
+            }),
+        );
+        rolled_back
+            .handle_failure(&attempted, anyhow!("Component broke."))
+            .await
+            .unwrap();
+        assert_eq!(rolled_back.state(), DeploymentState::RolledBack);
+        assert_eq!(rolled_back.information().status, "FAILED");
+        assert_eq!(
+            rolled_back.status_details().detailed_status.as_deref(),
+            Some(DETAILED_STATUS_FAILED_ROLLBACK_COMPLETE)
+        );
+        assert_eq!(
+            rolled_back.status_details().failure_cause.as_deref(),
+            Some("Component broke.")
+        );
+        assert_eq!(versions_of(&names), expected);
+        assert!(!component::deployed().contains(&"rollback-added".to_string()));
+
+        component::apply(vec![], &AtomicBool::new(false))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_deployments_with_their_snapshot() {
+        let _lock = component::TEST_LOCK.lock().await;
+        provisioning::test_configuration();
+        let names = ["resume-updated", "resume-added"];
+        let before = vec![component("resume-updated", "1.0.0", json!({}))];
+        component::apply(before, &AtomicBool::new(false))
+            .await
+            .unwrap();
+        let expected = versions_of(&names);
+
+        // Snapshotted and persisted, then stopped while applying.
+        let document = json!({
+            "configurationArn": "arn:configuration:thing/resume:1",
+            FAILURE_HANDLING_POLICY_KEY: FAILURE_HANDLING_POLICY_ROLLBACK
+        });
+        let mut deployment = Deployment::register("resume-test", DeploymentSource::Local, document)
+            .unwrap()
+            .unwrap();
+        deployment
+            .transition(DeploymentState::InProgress, StatusDetails::default())
+            .unwrap();
+        deployment.snapshot = Some(component::snapshot());
+        let path = std::env::temp_dir()
+            .join(format!("resume-test-{}", std::process::id()))
+            .join(ONGOING_DEPLOYMENT_FILE);
+        persist(&deployment, &path);
+        let attempted = vec![
+            component("resume-updated", "2.0.0", json!({})),
+            component("resume-added", "1.0.0", json!({})),
+        ];
+        component::apply(attempted.clone(), &AtomicBool::new(false))
+            .await
+            .unwrap();
+        assert_ne!(versions_of(&names), expected);
+
+        // The restarted nucleus has not seen the deployment yet.
+        DEPLOYMENTS.remove("resume-test");
+        let ongoing: OngoingDeployment = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(ongoing.stage, DeploymentStage::Default);
+        let mut resumed = restore(ongoing).unwrap().unwrap();
+        assert!(resumed.snapshot.is_some());
+        // Run again from the queue.
+        resumed
+            .transition(DeploymentState::InProgress, StatusDetails::default())
+            .unwrap();
+        resumed
+            .handle_failure(&attempted, anyhow!("Component broke."))
+            .await
+            .unwrap();
+        assert_eq!(resumed.state(), DeploymentState::RolledBack);
+        assert_eq!(versions_of(&names), expected);
+        assert!(!component::deployed().contains(&"resume-added".to_string()));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        component::apply(vec![], &AtomicBool::new(false))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_deployments_whose_nucleus_cannot_be_activated() {
+        provisioning::test_configuration();
+        let document = json!({ "configurationArn": "arn:configuration:thing/activation:1" });
+        let mut deployment =
+            Deployment::register("activation-test", DeploymentSource::Local, document)
+                .unwrap()
+                .unwrap();
+        deployment
+            .transition(DeploymentState::InProgress, StatusDetails::default())
+            .unwrap();
+        deployment.stage = DeploymentStage::KernelActivation;
+        deployment.restart = Some(kernel::REQUEST_RESTART);
+
+        deployment
+            .activation_failed(anyhow!("No new nucleus was prepared."))
+            .await
+            .unwrap();
+        assert_eq!(deployment.state(), DeploymentState::Failed);
+        assert_eq!(deployment.restart_requested(), None);
+        assert_eq!(
+            deployment.status_details().failure_cause.as_deref(),
+            Some("Failed to activate the nucleus update: No new nucleus was prepared.")
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_components_before_restarting() {
+        let _lock = component::TEST_LOCK.lock().await;
+        provisioning::test_configuration();
+        let mut running = component("restart-running", "1.0.0", json!({}));
+        running.recipe.lifecycle.run = Some(component::Script {
+            script: "echo $$ > pid && exec sleep 60".to_string(),
+            timeout: None,
+        });
+        component::apply(vec![running.clone()], &AtomicBool::new(false))
+            .await
+            .unwrap();
+        let pid = fs::read_to_string(component::work_path("restart-running").join("pid")).unwrap();
+        let process = PathBuf::from("/proc").join(pid.trim());
+        assert!(process.exists());
+
+        let document = json!({ "configurationArn": "arn:configuration:thing/restart:1" });
+        let mut deployment =
+            Deployment::register("restart-test", DeploymentSource::Local, document)
+                .unwrap()
+                .unwrap();
+        deployment
+            .transition(DeploymentState::InProgress, StatusDetails::default())
+            .unwrap();
+        deployment.stage = DeploymentStage::KernelActivation;
+        deployment.restart = Some(kernel::REQUEST_RESTART);
+        let path = std::env::temp_dir()
+            .join(format!("restart-test-{}", std::process::id()))
+            .join(ONGOING_DEPLOYMENT_FILE);
+        assert_eq!(
+            prepare_restart(&mut deployment, &path).await.unwrap(),
+            Some(kernel::REQUEST_RESTART)
+        );
+        assert!(!process.exists());
+
+        component::apply(vec![], &AtomicBool::new(false))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn names_deployment_groups() {
+        let group = json!({
+            "configurationArn": "arn:aws:greengrass:r:1:configuration:thinggroup/group:3"
+        });
+        assert_eq!(group_name(&group), "thinggroup/group");
+        let named = json!({ "configurationArn": "arn:1", "groupName": "thing/device" });
+        assert_eq!(group_name(&named), "thing/device");
+        let local = json!({ "configurationArn": "local-1" });
+        assert_eq!(group_name(&local), LOCAL_DEPLOYMENT_GROUP_NAME);
+    }
+}
@@ -0,0 +1,153 @@
+//! # Deployment groups
+//!
+//! A device gets a fleet configuration from every thing group it belongs to, and one for the
+//! thing itself. Each configuration only lists the root components of its own group, so the
+//! components of the latest successful configuration of every group are kept here, by group.
+//!
+//! A deployment applies the components of its group together with the components of the other
+//! groups: a component goes away only when no group needs it anymore, and two groups asking for
+//! different versions of a component fail the deployment. The groups are kept in
+//! `<root>/deployments/groups.json` across restarts.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+pub static DEPLOYMENT_GROUPS: Lazy<Mutex<DeploymentGroups>> =
+    Lazy::new(|| Mutex::new(DeploymentGroups::default()));
+
+/// The root components a group deployed, by name, with their version.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupComponents {
+    #[serde(rename = "configurationArn")]
+    pub configuration_arn: String,
+    pub components: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeploymentGroups {
+    groups: BTreeMap<String, GroupComponents>,
+}
+
+impl DeploymentGroups {
+    pub fn load(path: &Path) -> Self {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring invalid deployment groups: {}", e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_vec(self)?).context("Failed to save deployment groups.")
+    }
+
+    /**
+     * The versions of all the root components once `group` deploys `components`.
+     *
+     * The components of `group` replace the ones it deployed before, and the other groups keep
+     * theirs. A component both `group` and another group need must have the same version.
+     */
+    pub fn merge(
+        &self,
+        group: &str,
+        components: &BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut merged = components.clone();
+        for (other, deployed) in self.groups.iter().filter(|(other, _)| *other != group) {
+            for (name, version) in &deployed.components {
+                match merged.get(name) {
+                    Some(wanted) if wanted != version => bail!(
+                        "Component {} is required at version {} by {} but {} is requested.",
+                        name,
+                        version,
+                        other,
+                        wanted
+                    ),
+                    Some(_) => {}
+                    None => {
+                        merged.insert(name.clone(), version.clone());
+                    }
+                }
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Record the components `group` deployed.
+    pub fn set(&mut self, group: &str, components: GroupComponents) {
+        if components.components.is_empty() {
+            self.groups.remove(group);
+        } else {
+            self.groups.insert(group.to_string(), components);
+        }
+    }
+
+    /// Configuration ARNs of the groups that deployed component `name`.
+    pub fn fleet_config_arns(&self, name: &str) -> Vec<String> {
+        self.groups
+            .values()
+            .filter(|group| group.components.contains_key(name))
+            .map(|group| group.configuration_arn.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn components(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn merges_groups() {
+        let mut groups = DeploymentGroups::default();
+        groups.set(
+            "thinggroup/a",
+            GroupComponents {
+                configuration_arn: "arn:a:1".to_string(),
+                components: components(&[("shared", "1.0.0"), ("only-a", "1.0.0")]),
+            },
+        );
+        groups.set(
+            "thinggroup/b",
+            GroupComponents {
+                configuration_arn: "arn:b:3".to_string(),
+                components: components(&[("shared", "1.0.0")]),
+            },
+        );
+        assert_eq!(
+            groups.fleet_config_arns("shared"),
+            vec!["arn:a:1", "arn:b:3"]
+        );
+
+        // Group a drops "shared", which group b still needs.
+        let merged = groups
+            .merge("thinggroup/a", &components(&[("only-a", "2.0.0")]))
+            .unwrap();
+        assert_eq!(
+            merged,
+            components(&[("only-a", "2.0.0"), ("shared", "1.0.0")])
+        );
+
+        assert!(groups
+            .merge("thinggroup/b", &components(&[("shared", "2.0.0")]))
+            .is_err());
+    }
+}
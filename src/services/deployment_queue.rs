@@ -0,0 +1,238 @@
+//! # Deployment queue
+//!
+//! Deployments from the shadow, IoT Jobs and local sources all go through a single queue and are
+//! executed one at a time, in the order they were received. When a deployment is offered:
+//! - a duplicate of a configuration that is queued or running is dropped;
+//! - a newer shadow deployment supersedes a queued shadow deployment, since only the latest
+//!   desired state of the shadow matters;
+//! - a newer revision for the same target (thing or thing group) replaces the queued one in
+//!   place, while an older revision is dropped.
+//!
+//! Whatever is dropped or replaced is handed back to the caller to be canceled.
+//!
+//! Deployments canceled in the cloud are removed from the queue, and the running one is flagged
+//! so that it stops at its next safe point.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tracing::debug;
+
+use super::deployment::{Deployment, DeploymentSource};
+
+pub static DEPLOYMENT_QUEUE: Lazy<DeploymentQueue> = Lazy::new(DeploymentQueue::new);
+
+struct State {
+    queued: VecDeque<Deployment>,
+    running: Option<Running>,
+}
+
+/// The deployment being executed.
+struct Running {
+    configuration_arn: String,
+    source: DeploymentSource,
+    canceled: Arc<AtomicBool>,
+}
+
+pub struct DeploymentQueue {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl DeploymentQueue {
+    pub fn new() -> Self {
+        DeploymentQueue {
+            state: Mutex::new(State {
+                queued: VecDeque::new(),
+                running: None,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Queue `deployment`, returning the deployment that lost out to it, or itself if it was
+    /// dropped.
+    pub fn offer(&self, deployment: Deployment) -> Option<Deployment> {
+        let mut state = self.state.lock().unwrap();
+        let arn = deployment.configuration_arn.as_str();
+        if state.running.as_ref().map(|r| r.configuration_arn.as_str()) == Some(arn)
+            || state.queued.iter().any(|q| q.configuration_arn == arn)
+        {
+            debug!("Dropping duplicate deployment {}.", deployment.id);
+            return Some(deployment);
+        }
+        let superseded = state
+            .queued
+            .iter()
+            .enumerate()
+            .find_map(|(index, queued)| Some((index, is_newer(&deployment, queued)?)));
+        match superseded {
+            Some((index, true)) => {
+                debug!(
+                    "Deployment {} supersedes queued deployment {}.",
+                    deployment.id, state.queued[index].id
+                );
+                let old = mem::replace(&mut state.queued[index], deployment);
+                Some(old)
+            }
+            Some((index, false)) => {
+                debug!(
+                    "Dropping deployment {}, queued deployment {} is newer.",
+                    deployment.id, state.queued[index].id
+                );
+                Some(deployment)
+            }
+            None => {
+                state.queued.push_back(deployment);
+                drop(state);
+                self.notify.notify_one();
+                None
+            }
+        }
+    }
+
+    /// Wait for the next deployment and mark it as running until `done` is called.
+    pub async fn next(&self) -> Deployment {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(deployment) = state.queued.pop_front() {
+                    state.running = Some(Running {
+                        configuration_arn: deployment.configuration_arn.clone(),
+                        source: deployment.source.clone(),
+                        canceled: deployment.cancellation(),
+                    });
+                    return deployment;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn done(&self) {
+        self.state.lock().unwrap().running = None;
+    }
+
+    /// Cancel the deployments whose source matches `canceled`, returning the queued ones that
+    /// were removed. A matching running deployment is asked to stop at its next safe point.
+    pub fn cancel(&self, canceled: impl Fn(&DeploymentSource) -> bool) -> Vec<Deployment> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(running) = &state.running {
+            if canceled(&running.source) {
+                debug!(
+                    "Canceling running deployment {}.",
+                    running.configuration_arn
+                );
+                running.canceled.store(true, Ordering::SeqCst);
+            }
+        }
+        let (removed, kept): (VecDeque<_>, VecDeque<_>) = mem::take(&mut state.queued)
+            .into_iter()
+            .partition(|queued| canceled(&queued.source));
+        state.queued = kept;
+        removed.into()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for DeploymentQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `new` should replace the queued deployment `old`, or `None` if they are unrelated.
+fn is_newer(new: &Deployment, old: &Deployment) -> Option<bool> {
+    let shadow = |d: &Deployment| matches!(d.source, DeploymentSource::Shadow { .. });
+    if shadow(new) && shadow(old) {
+        return Some(true);
+    }
+    let (new_target, new_revision) = target_and_revision(&new.configuration_arn);
+    let (old_target, old_revision) = target_and_revision(&old.configuration_arn);
+    if new_target != old_target {
+        return None;
+    }
+    Some(new_revision > old_revision)
+}
+
+// "arn:aws:greengrass:<region>:<id>:configuration:thinggroup/<name>:<revision>"
+fn target_and_revision(arn: &str) -> (&str, Option<u64>) {
+    match arn.rsplit_once(':') {
+        Some((target, revision)) => (target, revision.parse().ok()),
+        None => (arn, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn deployment(id: &str, arn: &str) -> Deployment {
+        let document = json!({ "configurationArn": arn });
+        Deployment::register(id, DeploymentSource::Local, document)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn dedupes_and_supersedes() {
+        let group = "arn:aws:greengrass:r:1:configuration:thinggroup/queue-test";
+        let queue = DeploymentQueue::new();
+        assert!(queue
+            .offer(deployment("queue-1", &format!("{group}:1")))
+            .is_none());
+        let dropped = queue.offer(deployment("queue-2", &format!("{group}:1")));
+        assert_eq!(dropped.unwrap().id, "queue-2");
+
+        let replaced = queue.offer(deployment("queue-3", &format!("{group}:2")));
+        assert_eq!(replaced.unwrap().id, "queue-1");
+        let dropped = queue.offer(deployment("queue-4", &format!("{group}:1")));
+        assert_eq!(dropped.unwrap().id, "queue-4");
+
+        let other = "arn:aws:greengrass:r:1:configuration:thinggroup/other:1";
+        assert!(queue.offer(deployment("queue-5", other)).is_none());
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(queue.next().await.id, "queue-3");
+        let dropped = queue.offer(deployment("queue-6", &format!("{group}:2")));
+        assert_eq!(dropped.unwrap().id, "queue-6");
+        queue.done();
+        assert_eq!(queue.next().await.id, "queue-5");
+    }
+
+    #[tokio::test]
+    async fn cancels_queued_and_running() {
+        let queue = DeploymentQueue::new();
+        let shadow = DeploymentSource::Shadow {
+            thing_name: "thing".to_string(),
+        };
+        let register = |id: &str, source: DeploymentSource| {
+            let arn = format!("arn:aws:greengrass:r:1:configuration:thing/{id}:1");
+            Deployment::register(id, source, json!({ "configurationArn": arn }))
+                .unwrap()
+                .unwrap()
+        };
+        queue.offer(register("cancel-1", shadow.clone()));
+        let running = queue.next().await;
+        queue.offer(register("cancel-2", shadow));
+        queue.offer(register("cancel-3", DeploymentSource::Local));
+
+        let removed = queue.cancel(|source| matches!(source, DeploymentSource::Shadow { .. }));
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].id, "cancel-2");
+        assert!(running.is_canceled());
+        assert_eq!(queue.len(), 1);
+    }
+}
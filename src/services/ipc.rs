@@ -0,0 +1,285 @@
+//! # Component IPC
+//!
+//! Components reach the nucleus over a Unix socket, whose path they find in the
+//! `NUCLEUS_IPC_SOCKET` environment variable. Messages are JSON objects, one per line. The first
+//! one authenticates the component with the token in its `NUCLEUS_IPC_TOKEN` environment
+//! variable:
+//!
+//! ```json
+//! {"token": "<token>"}
+//! ```
+//!
+//! Every following request names an `operation`, and is answered with that `operation` and an
+//! `error` when it failed:
+//! - `SubscribeToComponentUpdates` streams `{"preUpdateEvent": {"deploymentId": ...}}` and
+//!   `{"postUpdateEvent": {"deploymentId": ...}}` until the connection closes;
+//! - `DeferComponentUpdate`, with `deploymentId`, `recheckAfterMs` and an optional `message`,
+//!   answers the pending `preUpdateEvent`.
+//!
+//! This is not Greengrass IPC: there is no EventStream handshake, so the Greengrass SDK clients
+//! cannot connect. The variables of Greengrass IPC (`SVCUID`,
+//! `AWS_GG_NUCLEUS_DOMAIN_SOCKET_FILEPATH_FOR_COMPONENT`) are left unset so that those clients
+//! fail up front instead of mid-handshake.
+//!
+//! A token is valid until its component stops.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use rand::distributions::{Alphanumeric, DistString};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info};
+
+use crate::services::component;
+use crate::services::policy::{self, ComponentUpdateEvent, ComponentUpdates};
+
+pub const SOCKET_PATH_ENV: &str = "NUCLEUS_IPC_SOCKET";
+pub const TOKEN_ENV: &str = "NUCLEUS_IPC_TOKEN";
+const SOCKET_FILE: &str = "nucleus-ipc.socket";
+const TOKEN_LENGTH: usize = 16;
+
+/// Components by the token they authenticate with.
+static TOKENS: Lazy<DashMap<String, String>> = Lazy::new(DashMap::new);
+/// Tokens by component.
+static ISSUED: Lazy<DashMap<String, String>> = Lazy::new(DashMap::new);
+
+pub fn socket_path() -> PathBuf {
+    component::root().join(SOCKET_FILE)
+}
+
+/// The token of `component`, created on first use.
+pub fn token(component: &str) -> String {
+    ISSUED
+        .entry(component.to_string())
+        .or_insert_with(|| {
+            let token = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH);
+            TOKENS.insert(token.clone(), component.to_string());
+            token
+        })
+        .clone()
+}
+
+/// Invalidate the token of `component`, which stopped.
+pub fn revoke(component: &str) {
+    if let Some((_, token)) = ISSUED.remove(component) {
+        TOKENS.remove(&token);
+    }
+}
+
+/// Listen for components on `path`, replacing the socket of a previous run.
+pub fn start(path: &Path) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    info!("Serving component IPC on {}.", path.display());
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream).await {
+                            debug!("IPC connection closed: {:#}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept IPC connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn serve(stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let hello = lines
+        .next_line()
+        .await?
+        .context("Connection closed before authenticating.")?;
+    let component = serde_json::from_str::<Value>(&hello)
+        .ok()
+        .and_then(|hello| hello["token"].as_str().map(str::to_string))
+        .and_then(|token| {
+            TOKENS
+                .get(&token)
+                .map(|component| component.value().clone())
+        });
+    let Some(component) = component else {
+        send(&mut writer, &reply("Authenticate", Some("Invalid token."))).await?;
+        bail!("Component failed to authenticate.");
+    };
+    debug!("Component {} connected to IPC.", component);
+    send(&mut writer, &reply("Authenticate", None)).await?;
+
+    let mut updates: Option<ComponentUpdates> = None;
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                let response = handle(&component, &line, &mut updates);
+                send(&mut writer, &response).await?;
+            }
+            event = async { updates.as_mut().unwrap().recv().await }, if updates.is_some() => {
+                let event = match event {
+                    Ok(ComponentUpdateEvent::PreComponentUpdate { deployment_id }) => {
+                        json!({ "preUpdateEvent": { "deploymentId": deployment_id } })
+                    }
+                    Ok(ComponentUpdateEvent::PostComponentUpdate { deployment_id }) => {
+                        json!({ "postUpdateEvent": { "deploymentId": deployment_id } })
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => {
+                        updates = None;
+                        continue;
+                    }
+                };
+                send(&mut writer, &event).await?;
+            }
+        }
+    }
+}
+
+fn handle(component: &str, line: &str, updates: &mut Option<ComponentUpdates>) -> Value {
+    let Ok(request) = serde_json::from_str::<Value>(line) else {
+        return reply("", Some("Request is not JSON."));
+    };
+    let operation = request["operation"].as_str().unwrap_or_default();
+    match operation {
+        "SubscribeToComponentUpdates" => {
+            updates.get_or_insert_with(|| policy::subscribe_to_component_updates(component));
+            reply(operation, None)
+        }
+        "DeferComponentUpdate" => {
+            let Some(deployment_id) = request["deploymentId"].as_str() else {
+                return reply(operation, Some("Missing deploymentId."));
+            };
+            let recheck_after =
+                Duration::from_millis(request["recheckAfterMs"].as_u64().unwrap_or(0));
+            let message = request["message"].as_str().map(str::to_string);
+            policy::defer_component_update(component, deployment_id, recheck_after, message);
+            reply(operation, None)
+        }
+        _ => reply(operation, Some("Unsupported operation.")),
+    }
+}
+
+fn reply(operation: &str, error: Option<&str>) -> Value {
+    json!({ "operation": operation, "error": error })
+}
+
+async fn send(writer: &mut (impl AsyncWrite + Unpin), message: &Value) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::Lines;
+    use tokio::net::unix::OwnedReadHalf;
+
+    async fn next(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Value {
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_component_updates() {
+        let _guard = policy::TEST_LOCK.lock().await;
+        let dir = std::env::temp_dir().join(format!("ipc-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SOCKET_FILE);
+        start(&path).unwrap();
+
+        let (reader, mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+        send(&mut writer, &json!({ "token": "not-a-token" }))
+            .await
+            .unwrap();
+        assert_eq!(next(&mut lines).await["error"], "Invalid token.");
+
+        let (reader, mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let issued = token("com.example.Ipc");
+        assert_eq!(issued, token("com.example.Ipc"));
+        send(&mut writer, &json!({ "token": issued }))
+            .await
+            .unwrap();
+        assert_eq!(next(&mut lines).await, reply("Authenticate", None));
+        send(
+            &mut writer,
+            &json!({ "operation": "SubscribeToComponentUpdates" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            next(&mut lines).await,
+            reply("SubscribeToComponentUpdates", None)
+        );
+
+        let update = tokio::spawn(async {
+            policy::wait_for_safe_update("ipc-test", &policy::ComponentUpdatePolicy::default())
+                .await
+        });
+        assert_eq!(
+            next(&mut lines).await["preUpdateEvent"]["deploymentId"],
+            "ipc-test"
+        );
+        send(
+            &mut writer,
+            &json!({ "operation": "DeferComponentUpdate", "recheckAfterMs": 0 }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            next(&mut lines).await,
+            reply("DeferComponentUpdate", Some("Missing deploymentId."))
+        );
+        send(
+            &mut writer,
+            &json!({
+                "operation": "DeferComponentUpdate",
+                "deploymentId": "ipc-test",
+                "recheckAfterMs": 0,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(next(&mut lines).await, reply("DeferComponentUpdate", None));
+        tokio::time::timeout(Duration::from_secs(5), update)
+            .await
+            .unwrap()
+            .unwrap();
+
+        policy::component_update_done("ipc-test");
+        assert_eq!(
+            next(&mut lines).await["postUpdateEvent"]["deploymentId"],
+            "ipc-test"
+        );
+
+        revoke("com.example.Ipc");
+        let (reader, mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+        send(&mut writer, &json!({ "token": issued }))
+            .await
+            .unwrap();
+        assert_eq!(next(&mut lines).await["error"], "Invalid token.");
+        assert_ne!(token("com.example.Ipc"), issued);
+        revoke("com.example.Ipc");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
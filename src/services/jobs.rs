@@ -0,0 +1,537 @@
+//! # IoT Jobs deployments
+//!
+//! Thing group deployments reach the device as AWS IoT jobs whose job document is the fleet
+//! configuration. The nucleus listens on `notify-next`, describes the next pending job
+//! execution and queues it with the other deployments. Once the deployment queue starts it, the
+//! execution is claimed by moving it to `IN_PROGRESS` and ends as `SUCCEEDED` or `FAILED`.
+//!
+//! Every update carries the `expectedVersion` of the execution. The latest version of each
+//! execution is taken from describe responses and from `update/accepted`. An update rejected with
+//! `VersionMismatch` means the execution changed in the cloud in the meantime, so its job is
+//! described again and the update is sent again with the new version. The next job is also
+//! described whenever the MQTT connection comes up, since notifications sent while the device
+//! was offline are lost.
+//!
+//! A job canceled in the cloud is no longer the next pending job, so a deployment job that is
+//! not the next one is canceled on the device.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Error, Result};
+use aws_iot_device_sdk::jobs::{self, Topic};
+use bytes::Bytes;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use rumqttc::QoS;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, info, warn};
+
+use crate::mqtt::{
+    Callback, ConnectionState, Message, MqttClient, PublishRequest, SubscribeRequest,
+};
+use crate::services::deployment::{self, Deployment, DeploymentSource, DeploymentState};
+use crate::services::status::StatusDetails;
+
+const NEXT_JOB: &str = "$next";
+const JOB_QUEUED: &str = "QUEUED";
+const JOB_IN_PROGRESS: &str = "IN_PROGRESS";
+const JOB_SUCCEEDED: &str = "SUCCEEDED";
+const JOB_FAILED: &str = "FAILED";
+const DETAILED_STATUS_KEY: &str = "detailed-deployment-status";
+const FAILURE_CAUSE_KEY: &str = "deployment-failure-cause";
+const VERSION_MISMATCH: &str = "VersionMismatch";
+
+/// Latest known state of the job execution of each deployment job, by job id.
+static EXECUTIONS: Lazy<DashMap<String, ExecutionState>> = Lazy::new(DashMap::new);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ExecutionState {
+    version: i64,
+    in_progress: bool,
+    /// Status and status details of the last update that was not accepted yet.
+    pending: Option<(String, Value)>,
+}
+
+enum JobEvent {
+    /// Payload of a `notify-next` message or a describe response.
+    Execution { topic: String, payload: Bytes },
+    /// An update request was accepted.
+    Accepted { topic: String, payload: Bytes },
+    /// A describe or update request was rejected.
+    Rejected { topic: String, payload: Bytes },
+}
+
+/// A job execution as sent by the Jobs service.
+#[derive(Debug)]
+struct JobExecution {
+    job_id: String,
+    status: String,
+    version: i64,
+    document: Value,
+}
+
+impl JobExecution {
+    /// Parse the `execution` of a message, or `None` when there is no pending job.
+    fn from_payload(payload: &[u8]) -> Result<Option<Self>> {
+        let message: Value =
+            serde_json::from_slice(payload).context("Failed to deserialize job execution.")?;
+        let execution = match message.get("execution") {
+            Some(execution) if !execution.is_null() => execution,
+            _ => return Ok(None),
+        };
+        Ok(Some(JobExecution {
+            job_id: execution["jobId"]
+                .as_str()
+                .context("Job execution has no job id.")?
+                .to_string(),
+            status: execution["status"]
+                .as_str()
+                .context("Job execution has no status.")?
+                .to_string(),
+            version: execution["versionNumber"]
+                .as_i64()
+                .context("Job execution has no version number.")?,
+            document: execution["jobDocument"].clone(),
+        }))
+    }
+
+    /// Record the version and status of this execution, returning the update to send again if
+    /// the last one was not accepted.
+    fn record(&self) -> Option<(String, Value)> {
+        let mut state = EXECUTIONS.entry(self.job_id.clone()).or_default();
+        state.version = self.version;
+        state.in_progress = self.status == JOB_IN_PROGRESS;
+        match state.pending.take() {
+            Some((status, _)) if status == self.status => None,
+            pending => {
+                state.pending = pending.clone();
+                pending
+            }
+        }
+    }
+}
+
+/// Record the execution state of an `update/accepted` response for job `job_id`.
+fn record_accepted(job_id: &str, payload: &[u8]) -> Result<()> {
+    let response: Value =
+        serde_json::from_slice(payload).context("Failed to deserialize job update response.")?;
+    let execution = &response["executionState"];
+    let status = execution["status"]
+        .as_str()
+        .context("Job update response has no status.")?;
+    let version = execution["versionNumber"]
+        .as_i64()
+        .context("Job update response has no version number.")?;
+    if status != JOB_QUEUED && status != JOB_IN_PROGRESS {
+        EXECUTIONS.remove(job_id);
+        return Ok(());
+    }
+    let mut state = EXECUTIONS.entry(job_id.to_string()).or_default();
+    state.version = version;
+    state.in_progress = status == JOB_IN_PROGRESS;
+    if state
+        .pending
+        .as_ref()
+        .is_some_and(|(pending, _)| pending == status)
+    {
+        state.pending = None;
+    }
+    Ok(())
+}
+
+/// The job id in a Jobs API topic, `None` for `notify-next` and `$next` requests.
+fn topic_job_id(topic: &str) -> Option<String> {
+    let id = jobs::match_topic(topic).ok()?.id?;
+    (&id[..] != NEXT_JOB).then(|| id.to_string())
+}
+
+/// Subscribe to the Jobs topics of `thing_name` and start processing deployment jobs.
+pub async fn connect(mqtt_client: &MqttClient, thing_name: &str) -> Result<()> {
+    let (tx, events) = mpsc::unbounded_channel();
+
+    let sender = tx.clone();
+    let execution: Callback = Arc::new(move |message: &Message| {
+        let _ = sender.send(JobEvent::Execution {
+            topic: message.topic.clone(),
+            payload: message.payload.clone(),
+        });
+    });
+    for topic in [Topic::NextJobChanged, Topic::DescribeSuccess] {
+        mqtt_client
+            .subscribe(SubscribeRequest {
+                topic: topic_for(thing_name, topic)?,
+                qos: QoS::AtLeastOnce,
+                callback: execution.clone(),
+            })
+            .await?;
+    }
+
+    let sender = tx.clone();
+    let accepted: Callback = Arc::new(move |message: &Message| {
+        let _ = sender.send(JobEvent::Accepted {
+            topic: message.topic.clone(),
+            payload: message.payload.clone(),
+        });
+    });
+    mqtt_client
+        .subscribe(SubscribeRequest {
+            topic: topic_for(thing_name, Topic::UpdateSuccess)?,
+            qos: QoS::AtLeastOnce,
+            callback: accepted,
+        })
+        .await?;
+
+    let rejected: Callback = Arc::new(move |message: &Message| {
+        let _ = tx.send(JobEvent::Rejected {
+            topic: message.topic.clone(),
+            payload: message.payload.clone(),
+        });
+    });
+    for topic in [Topic::DescribeFailed, Topic::UpdateFailed] {
+        mqtt_client
+            .subscribe(SubscribeRequest {
+                topic: topic_for(thing_name, topic)?,
+                qos: QoS::AtLeastOnce,
+                callback: rejected.clone(),
+            })
+            .await?;
+    }
+
+    let helper = JobsHelper {
+        mqtt_client: mqtt_client.clone(),
+        thing_name: thing_name.to_string(),
+    };
+    tokio::spawn(helper.run(events, mqtt_client.connection_state()));
+    Ok(())
+}
+
+/// Job execution status details use their own keys for the deployment status details.
+fn job_status_details(details: &StatusDetails) -> Value {
+    let mut job_details = json!({});
+    if let Some(detailed_status) = &details.detailed_status {
+        job_details[DETAILED_STATUS_KEY] = json!(detailed_status);
+    }
+    if let Some(failure_cause) = &details.failure_cause {
+        job_details[FAILURE_CAUSE_KEY] = json!(failure_cause);
+    }
+    job_details
+}
+
+fn topic_for(thing_name: &str, topic: Topic) -> Result<String> {
+    let topic = jobs::assemble_topic(thing_name, topic).map_err(Error::msg)?;
+    Ok(topic.to_string())
+}
+
+#[doc(alias = "IotJobsHelper")]
+struct JobsHelper {
+    mqtt_client: MqttClient,
+    thing_name: String,
+}
+
+impl JobsHelper {
+    async fn run(
+        mut self,
+        mut events: mpsc::UnboundedReceiver<JobEvent>,
+        mut state: watch::Receiver<ConnectionState>,
+    ) {
+        let mut connected = false;
+        loop {
+            let now = state.borrow_and_update().is_connected();
+            if now && !connected {
+                self.describe_next().await;
+            }
+            connected = now;
+            tokio::select! {
+                changed = state.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+                event = events.recv() => match event {
+                    Some(JobEvent::Execution { topic, payload }) => {
+                        let handled = match topic_job_id(&topic) {
+                            Some(job_id) => self.handle_described(&job_id, &payload).await,
+                            None => self.handle_execution(&payload).await,
+                        };
+                        if let Err(e) = handled {
+                            error!("Failed to process deployment job: {:#}", e);
+                        }
+                    }
+                    Some(JobEvent::Accepted { topic, payload }) => {
+                        if let Some(job_id) = topic_job_id(&topic) {
+                            if let Err(e) = record_accepted(&job_id, &payload) {
+                                warn!("Failed to process deployment job update: {:#}", e);
+                            }
+                        }
+                    }
+                    Some(JobEvent::Rejected { topic, payload }) => {
+                        self.handle_rejected(&topic, &payload).await
+                    }
+                    None => return,
+                },
+            }
+        }
+    }
+
+    async fn describe_next(&self) {
+        if let Err(e) = self.request_next_job().await {
+            warn!("Failed to request the next deployment job: {:#}", e);
+        }
+    }
+
+    #[doc(alias = "requestNextPendingJobDocument")]
+    async fn request_next_job(&self) -> Result<()> {
+        self.request_job(NEXT_JOB).await
+    }
+
+    async fn request_job(&self, job_id: &str) -> Result<()> {
+        let topic = jobs::describe(&self.thing_name, job_id).map_err(Error::msg)?;
+        let payload = json!({ "includeJobDocument": true });
+        self.mqtt_client
+            .publish(PublishRequest::new(topic.as_str(), payload.to_string()).qos(QoS::AtLeastOnce))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_execution(&mut self, payload: &[u8]) -> Result<()> {
+        let execution = JobExecution::from_payload(payload)?;
+        let next = execution.as_ref().map(|execution| execution.job_id.clone());
+        deployment::cancel(&self.mqtt_client, |source| {
+            matches!(source, DeploymentSource::Jobs { job_id, .. } if Some(job_id) != next.as_ref())
+        })
+        .await?;
+        let execution = match execution {
+            Some(execution) => execution,
+            None => {
+                debug!("No pending deployment job.");
+                return Ok(());
+            }
+        };
+        if execution.status != JOB_QUEUED && execution.status != JOB_IN_PROGRESS {
+            debug!(
+                "Ignoring deployment job {} in status {}.",
+                execution.job_id, execution.status
+            );
+            return Ok(());
+        }
+        execution.record();
+        let source = DeploymentSource::Jobs {
+            thing_name: self.thing_name.clone(),
+            job_id: execution.job_id.clone(),
+            version: execution.version,
+            in_progress: execution.status == JOB_IN_PROGRESS,
+        };
+        match Deployment::register(&execution.job_id, source, execution.document)? {
+            Some(deployment) => {
+                info!("Received deployment job {}.", execution.job_id);
+                deployment::enqueue(&self.mqtt_client, deployment).await
+            }
+            None => {
+                debug!("Deployment job {} was already received.", execution.job_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Send the update of job `job_id` that was rejected again, with the version just described.
+    async fn handle_described(&self, job_id: &str, payload: &[u8]) -> Result<()> {
+        let execution = match JobExecution::from_payload(payload)? {
+            Some(execution) => execution,
+            None => return Ok(()),
+        };
+        if let Some((status, details)) = execution.record() {
+            info!(
+                "Updating deployment job {} to {} again at version {}.",
+                job_id, status, execution.version
+            );
+            update(
+                &self.mqtt_client,
+                &self.thing_name,
+                job_id,
+                &status,
+                execution.version,
+                details,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_rejected(&self, topic: &str, payload: &[u8]) {
+        let response: Value = serde_json::from_slice(payload).unwrap_or_default();
+        warn!(
+            "Jobs request rejected on {}: {}",
+            topic,
+            response["message"].as_str().unwrap_or_default()
+        );
+        if response["code"] != VERSION_MISMATCH {
+            return;
+        }
+        // The execution changed in the cloud; look at its current state.
+        let updated = jobs::match_topic(topic).is_ok_and(|topic| topic.api == Topic::UpdateFailed);
+        match topic_job_id(topic).filter(|_| updated) {
+            Some(job_id) => {
+                if let Err(e) = self.request_job(&job_id).await {
+                    warn!("Failed to request deployment job {}: {:#}", job_id, e);
+                }
+            }
+            None => self.describe_next().await,
+        }
+    }
+}
+
+/// Report the state of a deployment job in its job execution.
+pub(crate) async fn report(mqtt_client: &MqttClient, deployment: &mut Deployment) -> Result<()> {
+    let state = deployment.state();
+    let details = job_status_details(deployment.status_details());
+    let DeploymentSource::Jobs {
+        thing_name,
+        job_id,
+        version,
+        in_progress,
+    } = &mut deployment.source
+    else {
+        return Ok(());
+    };
+    let status = match state {
+        DeploymentState::Queued => return Ok(()),
+        DeploymentState::InProgress if *in_progress => return Ok(()),
+        DeploymentState::InProgress => JOB_IN_PROGRESS,
+        DeploymentState::Succeeded => JOB_SUCCEEDED,
+        DeploymentState::Failed | DeploymentState::RolledBack => JOB_FAILED,
+        // Only the Jobs service can cancel a job execution.
+        DeploymentState::Canceled => return Ok(()),
+    };
+    let expected_version = {
+        let mut execution = EXECUTIONS.entry(job_id.clone()).or_insert(ExecutionState {
+            version: *version,
+            in_progress: *in_progress,
+            pending: None,
+        });
+        if state == DeploymentState::InProgress && execution.in_progress {
+            return Ok(());
+        }
+        execution.pending = Some((status.to_string(), details.clone()));
+        // Keep the latest known version with the deployment, in case it is persisted.
+        *version = execution.version;
+        *in_progress = execution.in_progress;
+        execution.version
+    };
+    update(
+        mqtt_client,
+        thing_name,
+        job_id,
+        status,
+        expected_version,
+        details,
+    )
+    .await
+}
+
+#[doc(alias = "updateJobStatus")]
+async fn update(
+    mqtt_client: &MqttClient,
+    thing_name: &str,
+    job_id: &str,
+    status: &str,
+    expected_version: i64,
+    details: Value,
+) -> Result<()> {
+    let topic = jobs::update(thing_name, job_id).map_err(Error::msg)?;
+    let payload = json!({
+        "status": status,
+        "expectedVersion": expected_version,
+        "statusDetails": details,
+        "includeJobExecutionState": true,
+    });
+    mqtt_client
+        .publish(PublishRequest::new(topic.as_str(), payload.to_string()).qos(QoS::AtLeastOnce))
+        .await
+        .with_context(|| format!("Failed to update deployment job {job_id} to {status}."))?;
+    debug!("Updated deployment job {} to {}.", job_id, status);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_job_execution() {
+        let payload = json!({
+            "timestamp": 1,
+            "execution": {
+                "jobId": "job-1",
+                "status": "QUEUED",
+                "versionNumber": 3,
+                "jobDocument": { "configurationArn": "arn:thinggroup/group:1" }
+            }
+        });
+        let execution = JobExecution::from_payload(payload.to_string().as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(execution.job_id, "job-1");
+        assert_eq!(execution.status, JOB_QUEUED);
+        assert_eq!(execution.version, 3);
+        assert_eq!(
+            deployment::configuration_arn(&execution.document).unwrap(),
+            "arn:thinggroup/group:1"
+        );
+
+        let empty = json!({ "timestamp": 1 }).to_string();
+        assert!(JobExecution::from_payload(empty.as_bytes())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn tracks_execution_versions() {
+        let job_id = "version-test";
+        let described = |status: &str, version: i64| JobExecution {
+            job_id: job_id.to_string(),
+            status: status.to_string(),
+            version,
+            document: Value::Null,
+        };
+        let accepted = |status: &str, version: i64| {
+            json!({ "executionState": { "status": status, "versionNumber": version } }).to_string()
+        };
+
+        // A job already received is described again after a VersionMismatch.
+        assert_eq!(described(JOB_QUEUED, 1).record(), None);
+        assert_eq!(described(JOB_QUEUED, 4).record(), None);
+        assert_eq!(EXECUTIONS.get(job_id).unwrap().version, 4);
+
+        // The update to IN_PROGRESS was rejected, and is sent again at the described version.
+        EXECUTIONS.get_mut(job_id).unwrap().pending =
+            Some((JOB_IN_PROGRESS.to_string(), json!({})));
+        assert_eq!(
+            described(JOB_QUEUED, 5).record(),
+            Some((JOB_IN_PROGRESS.to_string(), json!({})))
+        );
+
+        // Its acceptance carries the next version.
+        record_accepted(job_id, accepted(JOB_IN_PROGRESS, 6).as_bytes()).unwrap();
+        let execution = EXECUTIONS.get(job_id).unwrap().clone();
+        assert_eq!(
+            execution,
+            ExecutionState {
+                version: 6,
+                in_progress: true,
+                pending: None,
+            }
+        );
+
+        record_accepted(job_id, accepted(JOB_SUCCEEDED, 7).as_bytes()).unwrap();
+        assert!(EXECUTIONS.get(job_id).is_none());
+        assert_eq!(
+            topic_job_id("$aws/things/thing/jobs/job-1/update/accepted").as_deref(),
+            Some("job-1")
+        );
+        assert_eq!(
+            topic_job_id("$aws/things/thing/jobs/$next/get/accepted"),
+            None
+        );
+    }
+}
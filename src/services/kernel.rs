@@ -1,7 +1,13 @@
+use tracing::info;
+
 use crate::services::{Service, SERVICES};
 
 pub const VERSION: &str = "2.5.6";
-const NAME: &str = "aws.greengrass.Nucleus";
+pub const NAME: &str = "aws.greengrass.Nucleus";
+/// Exit code asking the service manager to launch the nucleus again.
+pub const REQUEST_RESTART: i32 = 100;
+/// Exit code asking the service manager to reboot the device.
+pub const REQUEST_REBOOT: i32 = 101;
 pub struct Kernel {}
 
 impl Service for Kernel {
@@ -11,3 +17,16 @@ impl Service for Kernel {
 }
 
 pub fn new() {}
+
+/// Exit with `code`, `REQUEST_RESTART` or `REQUEST_REBOOT`, for the service manager to act on.
+pub fn restart(code: i32) -> ! {
+    info!("Exiting with {} to restart.", code);
+    std::process::exit(code)
+}
+
+/// Version of the running nucleus.
+pub fn version() -> String {
+    SERVICES
+        .get(NAME)
+        .map_or(VERSION.to_string(), |service| service.version().to_string())
+}
@@ -1,9 +1,13 @@
 use anyhow::{Context, Error, Ok, Result};
 use clap::Args;
-use rumqttc::Publish;
-use tokio::sync::mpsc;
 
+pub mod alts;
+pub mod component;
 pub mod deployment;
+pub mod deployment_groups;
+pub mod deployment_queue;
+pub mod ipc;
+pub mod jobs;
 pub mod kernel;
 pub mod main;
 pub mod policy;
@@ -11,13 +15,43 @@ pub mod status;
 pub mod telemetry;
 
 use crate::dependency::State;
+use crate::mqtt::MqttClient;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 use dashmap::DashMap;
 use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use tracing::error;
 
-pub static SERVICES: Lazy<DashMap<String, ServiceStatus>> = Lazy::new(|| DashMap::new());
+pub static SERVICES: Lazy<DashMap<String, ServiceStatus>> = Lazy::new(DashMap::new);
+
+/// Lifecycle state changes of every service, for listeners such as the fleet status service.
+pub static STATE_CHANGES: Lazy<broadcast::Sender<StateChange>> =
+    Lazy::new(|| broadcast::channel(64).0);
+
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    pub component: String,
+    pub old: State,
+    pub new: State,
+}
+
+/// Record the lifecycle state of `component`, notifying listeners if it changed.
+pub fn report_state(component: &str, state: State) {
+    let old = match SERVICES.get_mut(component) {
+        Some(mut service) => std::mem::replace(&mut service.status, state.clone()),
+        None => return,
+    };
+    if old != state {
+        // Nobody listening is fine.
+        let _ = STATE_CHANGES.send(StateChange {
+            component: component.to_string(),
+            old,
+            new: state,
+        });
+    }
+}
 
 /// ```
 /// /// Some documentation.
@@ -26,12 +60,12 @@ pub static SERVICES: Lazy<DashMap<String, ServiceStatus>> = Lazy::new(|| DashMap
 /// let foo = "foo";
 /// assert_eq!(foo, "foo");
 /// ```
-
 pub trait Service {
+    #[allow(clippy::new_ret_no_self)]
     fn new(name: &'static str, ver: &'static str) -> ServiceStatus {
         ServiceStatus {
-            component_name: name,
-            version: ver,
+            component_name: name.to_string(),
+            version: ver.to_string(),
             fleetconfig_arns: vec![],
             status_details: json!(null),
             is_root: false,
@@ -49,8 +83,8 @@ pub trait Service {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServiceStatus {
     #[serde(rename = "componentName")]
-    component_name: &'static str,
-    version: &'static str,
+    component_name: String,
+    version: String,
     #[serde(rename = "fleetConfigArns")]
     fleetconfig_arns: Vec<String>,
     #[serde(rename = "statusDetails")]
@@ -61,6 +95,36 @@ pub struct ServiceStatus {
     status: State,
 }
 
+impl ServiceStatus {
+    /// Status of a component deployed by a deployment, which makes it a root component.
+    pub fn component(name: &str, version: &str) -> Self {
+        ServiceStatus {
+            component_name: name.to_string(),
+            version: version.to_string(),
+            fleetconfig_arns: vec![],
+            status_details: json!(null),
+            is_root: true,
+            status: State::NEW,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.component_name
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn status(&self) -> &State {
+        &self.status
+    }
+
+    pub fn set_fleet_config_arns(&mut self, arns: Vec<String>) {
+        self.fleetconfig_arns = arns;
+    }
+}
+
 use deployment::Deployments;
 use kernel::Kernel;
 use main::Main;
@@ -68,14 +132,23 @@ use policy::Policy;
 use status::Status;
 use telemetry::Telemetry;
 
-pub async fn start_services(tx: mpsc::Sender<Publish>) -> Result<()> {
+pub async fn start_services(mqtt_client: MqttClient) -> Result<()> {
     Kernel::enable();
     Main::enable();
     Policy::enable();
     Deployments::enable();
     Telemetry::enable();
     Status::enable();
-    status::start(tx).await?;
+    if let Err(e) = ipc::start(&ipc::socket_path()) {
+        error!("Components cannot reach the nucleus: {:#}", e);
+    }
+    let deployments = mqtt_client.clone();
+    tokio::spawn(async move {
+        // Deployments change the components, so they go after the ones of the last run start.
+        component::start_deployed().await;
+        deployment::process_deployments(deployments).await
+    });
+    status::start(mqtt_client).await?;
     Ok(())
 }
 #[cfg(test)]
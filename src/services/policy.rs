@@ -1,7 +1,33 @@
+//! # Update system policy
+//!
+//! Components that need to get ready before a deployment restarts them, for instance to flush
+//! their state, subscribe to component updates. When a deployment's `componentUpdatePolicy` is
+//! `NOTIFY_COMPONENTS`, every subscriber gets a `PreComponentUpdate` event before components are
+//! touched and answers with `defer_component_update`:
+//! - a zero `recheck_after` means the component is ready;
+//! - a positive `recheck_after` asks to be notified again after that long.
+//!
+//! The update goes ahead once every subscriber is ready, or when the policy timeout expires
+//! without any deferral. A `PostComponentUpdate` event follows the update.
+//!
+//! Components subscribe and defer through the `ipc` service; the functions here hold the
+//! subscriptions and deferrals of the nucleus.
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use tokio::sync::{broadcast, Notify};
+use tokio::time::{sleep, Instant};
+use tracing::{debug, info};
+
 use crate::services::{Service, SERVICES};
 
 const VERSION: &str = "0.0.0";
 const NAME: &str = "UpdateSystemPolicyService";
+const DEFAULT_TIMEOUT_SECONDS: u64 = 60;
+
 pub struct Policy {}
 
 impl Service for Policy {
@@ -9,3 +35,257 @@ impl Service for Policy {
         SERVICES.insert(NAME.to_string(), Self::new(NAME, VERSION));
     }
 }
+
+static COMPONENT_UPDATES: Lazy<broadcast::Sender<ComponentUpdateEvent>> =
+    Lazy::new(|| broadcast::channel(16).0);
+/// Components subscribed to component updates.
+static SUBSCRIBERS: Lazy<DashMap<String, ()>> = Lazy::new(DashMap::new);
+/// Answers to the pending `PreComponentUpdate`, by component.
+static DEFERRALS: Lazy<DashMap<String, Deferral>> = Lazy::new(DashMap::new);
+static DEFERRED: Lazy<Notify> = Lazy::new(Notify::new);
+/// Serializes the tests that subscribe to component updates.
+#[cfg(test)]
+pub(crate) static TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComponentUpdateEvent {
+    PreComponentUpdate { deployment_id: String },
+    PostComponentUpdate { deployment_id: String },
+}
+
+#[derive(Debug, Clone)]
+struct Deferral {
+    deployment_id: String,
+    recheck_after: Duration,
+    message: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum UpdateAction {
+    NotifyComponents,
+    SkipNotifyComponents,
+}
+
+/// The `componentUpdatePolicy` of a deployment document.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ComponentUpdatePolicy {
+    #[serde(rename = "action", default = "default_action")]
+    pub action: UpdateAction,
+    /// `timeout` in the fleet configuration sent to devices, `timeoutInSeconds` in the
+    /// CreateDeployment API.
+    #[serde(
+        rename = "timeoutInSeconds",
+        alias = "timeout",
+        default = "default_timeout_seconds"
+    )]
+    pub timeout_in_seconds: u64,
+}
+
+impl Default for ComponentUpdatePolicy {
+    fn default() -> Self {
+        Self {
+            action: default_action(),
+            timeout_in_seconds: default_timeout_seconds(),
+        }
+    }
+}
+
+fn default_action() -> UpdateAction {
+    UpdateAction::NotifyComponents
+}
+
+fn default_timeout_seconds() -> u64 {
+    DEFAULT_TIMEOUT_SECONDS
+}
+
+/// A subscription of a component to component updates, which ends when dropped.
+pub struct ComponentUpdates {
+    component: String,
+    events: broadcast::Receiver<ComponentUpdateEvent>,
+}
+
+impl ComponentUpdates {
+    pub async fn recv(&mut self) -> Result<ComponentUpdateEvent, broadcast::error::RecvError> {
+        self.events.recv().await
+    }
+}
+
+impl Drop for ComponentUpdates {
+    fn drop(&mut self) {
+        SUBSCRIBERS.remove(&self.component);
+        DEFERRED.notify_waiters();
+    }
+}
+
+#[doc(alias = "SubscribeToComponentUpdates")]
+pub fn subscribe_to_component_updates(component: &str) -> ComponentUpdates {
+    SUBSCRIBERS.insert(component.to_string(), ());
+    ComponentUpdates {
+        component: component.to_string(),
+        events: COMPONENT_UPDATES.subscribe(),
+    }
+}
+
+/// Answer the `PreComponentUpdate` of deployment `deployment_id` on behalf of `component`.
+#[doc(alias = "DeferComponentUpdate")]
+pub fn defer_component_update(
+    component: &str,
+    deployment_id: &str,
+    recheck_after: Duration,
+    message: Option<String>,
+) {
+    DEFERRALS.insert(
+        component.to_string(),
+        Deferral {
+            deployment_id: deployment_id.to_string(),
+            recheck_after,
+            message,
+        },
+    );
+    DEFERRED.notify_waiters();
+}
+
+/**
+ * Wait until the components subscribed to updates let deployment `deployment_id` go ahead.
+ *
+ * Subscribers are notified, then given up to the policy timeout to answer. The longest deferral
+ * is waited for before notifying them again; without any deferral the update goes ahead.
+ */
+pub async fn wait_for_safe_update(deployment_id: &str, policy: &ComponentUpdatePolicy) {
+    if policy.action == UpdateAction::SkipNotifyComponents {
+        return;
+    }
+    let timeout = Duration::from_secs(policy.timeout_in_seconds);
+    loop {
+        if SUBSCRIBERS.is_empty() {
+            return;
+        }
+        DEFERRALS.retain(|_, deferral| deferral.deployment_id != deployment_id);
+        debug!("Notifying components of deployment {}.", deployment_id);
+        let _ = COMPONENT_UPDATES.send(ComponentUpdateEvent::PreComponentUpdate {
+            deployment_id: deployment_id.to_string(),
+        });
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let deferred = DEFERRED.notified();
+            let answered = SUBSCRIBERS.iter().all(|subscriber| {
+                DEFERRALS
+                    .get(subscriber.key())
+                    .is_some_and(|deferral| deferral.deployment_id == deployment_id)
+            });
+            if answered {
+                break;
+            }
+            tokio::select! {
+                _ = deferred => {}
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+
+        let deferral = DEFERRALS
+            .iter()
+            .filter(|deferral| deferral.deployment_id == deployment_id)
+            .max_by_key(|deferral| deferral.recheck_after)
+            .map(|deferral| (deferral.key().clone(), deferral.value().clone()));
+        match deferral {
+            Some((component, deferral)) if !deferral.recheck_after.is_zero() => {
+                info!(
+                    "Component {} deferred deployment {} for {:?}: {}",
+                    component,
+                    deployment_id,
+                    deferral.recheck_after,
+                    deferral.message.unwrap_or_default()
+                );
+                sleep(deferral.recheck_after).await;
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Let subscribers know deployment `deployment_id` is done updating components.
+pub fn component_update_done(deployment_id: &str) {
+    DEFERRALS.retain(|_, deferral| deferral.deployment_id != deployment_id);
+    let _ = COMPONENT_UPDATES.send(ComponentUpdateEvent::PostComponentUpdate {
+        deployment_id: deployment_id.to_string(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_deferrals() {
+        let _guard = TEST_LOCK.lock().await;
+        let mut updates = subscribe_to_component_updates("com.example.Deferring");
+        let deferring = tokio::spawn(async move {
+            let mut deferred = false;
+            while let Ok(event) = updates.recv().await {
+                let ComponentUpdateEvent::PreComponentUpdate { deployment_id } = event else {
+                    break;
+                };
+                // Defer once, then accept.
+                let recheck_after = if deferred {
+                    Duration::ZERO
+                } else {
+                    Duration::from_millis(50)
+                };
+                deferred = true;
+                defer_component_update(
+                    "com.example.Deferring",
+                    &deployment_id,
+                    recheck_after,
+                    Some("flushing".to_string()),
+                );
+            }
+            deferred
+        });
+
+        let policy = ComponentUpdatePolicy::default();
+        let started = Instant::now();
+        wait_for_safe_update("policy-test", &policy).await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(started.elapsed() < Duration::from_secs(policy.timeout_in_seconds));
+        component_update_done("policy-test");
+        assert!(deferring.await.unwrap());
+
+        let policy: ComponentUpdatePolicy =
+            serde_json::from_str(r#"{ "action": "SKIP_NOTIFY_COMPONENTS" }"#).unwrap();
+        assert_eq!(policy.timeout_in_seconds, DEFAULT_TIMEOUT_SECONDS);
+        wait_for_safe_update("policy-test-skip", &policy).await;
+    }
+
+    #[test]
+    fn reads_the_policy_of_a_fleet_configuration() {
+        let document: serde_json::Value = serde_json::from_str(
+            r#"{
+                "configurationArn": "arn:aws:greengrass:us-east-1:123456789012:configuration:thing/MyThing:3",
+                "components": {
+                    "com.example.HelloWorld": {
+                        "version": "1.0.0",
+                        "configurationUpdate": { "merge": "{\"Message\":\"Hi\"}" },
+                        "runWith": {}
+                    }
+                },
+                "componentUpdatePolicy": { "timeout": 120, "action": "NOTIFY_COMPONENTS" },
+                "configurationValidationPolicy": { "timeout": 30 },
+                "failureHandlingPolicy": "ROLLBACK",
+                "creationTimestamp": 1662961328043,
+                "requiredCapabilities": ["LARGE_CONFIGURATION"]
+            }"#,
+        )
+        .unwrap();
+        let policy: ComponentUpdatePolicy =
+            serde_json::from_value(document["componentUpdatePolicy"].clone()).unwrap();
+        assert_eq!(
+            policy,
+            ComponentUpdatePolicy {
+                action: UpdateAction::NotifyComponents,
+                timeout_in_seconds: 120,
+            }
+        );
+    }
+}
@@ -19,8 +19,8 @@
 //!
 //! # Startup
 //! 1. FleetStatusService starts as a greengrass service, and is by default enabled. It
-//! starts a timer to update the information about all the components running
-//! in the Nucleus after a specific interval.
+//!    starts a timer to update the information about all the components running
+//!    in the Nucleus after a specific interval.
 //!
 //! # Shutdown
 //! Service lifecycle is managed by Nucleus. As part of Nucleus shutdown, FSS cancels the timer for cadence based data
@@ -38,28 +38,37 @@
 //! ` Note: this configuration cannot be updated via deployments.`
 //! ```text
 //! services:
-//!   main:
-//!     dependencies:
-//!       FleetStatusService
-//!   FleetStatusService:
+//!   aws.greengrass.Nucleus:
 //!     configuration:
-//!       periodicUpdateIntervalSec: 86400
+//!       fleetStatus:
+//!         periodicStatusPublishIntervalSeconds: 86400
 //! ```
+//!
+//! The sequence number and the time of the last periodic update are kept in
+//! `<root>/fleetStatus.json`, so a restart neither reuses sequence numbers nor publishes a
+//! periodic update early. No update is published while the MQTT connection is interrupted.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::dependency::State;
 use crate::{config, dependency, provisioning};
 use anyhow::{Context, Error, Ok, Result};
-use bytes::Bytes;
 use clap::Args;
-use rumqttc::{Publish, QoS};
+use once_cell::sync::Lazy;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::{
-    sync::mpsc,
-    time::{sleep, Duration},
-};
-use tracing::{debug, event, info, span, Level};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, event, info, span, warn, Level};
+
+use crate::mqtt::{chunked, ConnectionState, MqttClient, PublishRequest};
+use crate::platform::PLATFORM;
 
-use crate::services::{Service, ServiceStatus, SERVICES};
+use crate::services::{Service, ServiceStatus, SERVICES, STATE_CHANGES};
 
 use super::kernel;
 
@@ -74,84 +83,375 @@ impl Service for Status {
 }
 
 #[doc(alias = "uploadFleetStatusServiceData")]
-pub async fn start(tx: mpsc::Sender<Publish>) -> Result<()> {
-    let name = &provisioning::SystemConfiguration::global().thingName;
+pub async fn start(mqtt_client: MqttClient) -> Result<()> {
+    let system = provisioning::SystemConfiguration::global();
+    let name = &system.thingName;
+    let mut period = config::Config::global()
+        .services
+        .kernel
+        .configuration
+        .fleet_status
+        .periodic_status_publish_interval_seconds;
+    if period == 0 {
+        warn!(
+            "Invalid {}, using {} seconds.",
+            FLEET_STATUS_PERIODIC_PUBLISH_INTERVAL_SEC, DEFAULT_PERIODIC_PUBLISH_INTERVAL_SEC
+        );
+        period = DEFAULT_PERIODIC_PUBLISH_INTERVAL_SEC as u64;
+    }
+    let period = Duration::from_secs(period);
+    let mut connection = mqtt_client.connection_state();
+    let mut publisher = Publisher::new(mqtt_client, system.rootpath.join(FLEET_STATUS_RECORD_FILE));
+    let mut state_changes = STATE_CHANGES.subscribe();
+    let mut deployments = DEPLOYMENT_STATUS.subscribe();
+    // Spread the periodic updates of the fleet over the whole interval.
+    let jitter = rand::thread_rng().gen_range(Duration::ZERO..period);
 
     tokio::spawn(async move {
+        let next_update = |record: &UpdateRecord| {
+            Instant::now() + record.until_periodic_update(period, jitter, SystemTime::now())
+        };
+        let cadence = sleep_until(next_update(&publisher.record));
+        tokio::pin!(cadence);
+        let mut last_update = publisher.record.lastPeriodicUpdateTime;
+        let launch = async {
+            connection
+                .wait_for(ConnectionState::is_connected)
+                .await
+                .map(|_| ())
+        };
+        tokio::pin!(launch);
+        let mut launched = false;
+        // Components whose state changed since the last event-triggered update.
+        let mut updated = HashSet::new();
+        let mut deployment_in_progress = false;
         loop {
-            // println!("status service.");
-            let topic = format!("$aws/things/{}/greengrassv2/health/json", name);
-            let payload = json!(fss_data(name)).to_string();
-            info!(
-                event = "fss-status-update-published",
-                "Status update published to FSS"
-            );
-            tx.send(Publish {
-                dup: false,
-                qos: QoS::AtMostOnce,
-                retain: false,
-                pkid: 0,
-                topic: topic.to_string(),
-                payload: Bytes::from(payload.to_string()),
-            })
-            .await;
-            sleep(Duration::from_secs(86400)).await;
+            tokio::select! {
+                connected = &mut launch, if !launched => {
+                    launched = true;
+                    if connected.is_ok() {
+                        publisher.publish(fss_data(name, Trigger::NucleusLaunch, None)).await;
+                    }
+                }
+                () = &mut cadence => {
+                    publisher.publish(fss_data(name, Trigger::Cadence, None)).await;
+                    // Tried again after the jitter when it could not be published.
+                    cadence.as_mut().reset(next_update(&publisher.record));
+                }
+                change = state_changes.recv() => {
+                    let broken = match change {
+                        std::result::Result::Ok(change) => {
+                            updated.insert(change.component);
+                            change.new == State::BROKEN
+                        }
+                        Err(RecvError::Lagged(missed)) => {
+                            debug!("Missed {} state changes, reporting all components.", missed);
+                            updated.extend(SERVICES.iter().map(|r| r.key().clone()));
+                            false
+                        }
+                        Err(RecvError::Closed) => false,
+                    };
+                    // Changes during a deployment are reported once it completes.
+                    if broken && !deployment_in_progress {
+                        let details = fss_data(name, Trigger::BrokenComponent, Some(&updated));
+                        if publisher.publish(details).await {
+                            updated.clear();
+                        }
+                    }
+                }
+                status = deployments.recv() => {
+                    let (trigger, information) = match status {
+                        std::result::Result::Ok(status) => status,
+                        Err(e) => {
+                            warn!("Failed to receive deployment status: {}", e);
+                            continue;
+                        }
+                    };
+                    deployment_in_progress = information.status == DEPLOYMENT_IN_PROGRESS;
+                    if deployment_in_progress {
+                        continue;
+                    }
+                    let mut details = fss_data(name, trigger, Some(&updated));
+                    details.deploymentInformation = Some(information);
+                    if publisher.publish(details).await {
+                        updated.clear();
+                    }
+                }
+            }
+            // A complete update of any kind moves the next periodic one.
+            if publisher.record.lastPeriodicUpdateTime != last_update {
+                last_update = publisher.record.lastPeriodicUpdateTime;
+                cadence.as_mut().reset(next_update(&publisher.record));
+            }
         }
     });
     Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Sequence number and time of the last periodic update, kept across restarts.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+struct UpdateRecord {
+    sequenceNumber: u64,
+    /// Milliseconds since the Unix epoch.
+    lastPeriodicUpdateTime: u64,
+}
+
+impl UpdateRecord {
+    fn load(path: &Path) -> Self {
+        let record = fs::read(path)
+            .map_err(Error::from)
+            .and_then(|data| Ok(serde_json::from_slice(&data)?));
+        match record {
+            std::result::Result::Ok(record) => record,
+            Err(e) => {
+                debug!("No fleet status record loaded from {:?}: {}", path, e);
+                UpdateRecord::default()
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec(self)?).context("Failed to save fleet status record.")
+    }
+
+    /**
+     * Time from `now` until the next periodic update: `period` after the last one, which may
+     * predate a restart, but no sooner than `jitter` from now so that devices restarting together
+     * do not update together.
+     */
+    fn until_periodic_update(
+        &self,
+        period: Duration,
+        jitter: Duration,
+        now: SystemTime,
+    ) -> Duration {
+        let last = UNIX_EPOCH + Duration::from_millis(self.lastPeriodicUpdateTime);
+        (last + period)
+            .duration_since(now)
+            .unwrap_or(Duration::ZERO)
+            .max(jitter)
+    }
+}
+
+/// Numbers and publishes fleet status updates while the MQTT connection is up.
+struct Publisher {
+    mqtt_client: MqttClient,
+    path: PathBuf,
+    record: UpdateRecord,
+}
+
+impl Publisher {
+    fn new(mqtt_client: MqttClient, path: PathBuf) -> Self {
+        let record = UpdateRecord::load(&path);
+        Publisher {
+            mqtt_client,
+            path,
+            record,
+        }
+    }
+
+    /// Publish `details`, returning whether it was sent.
+    async fn publish(&mut self, mut details: FleetStatusDetails) -> bool {
+        if !self.mqtt_client.connected() {
+            info!("Not updating fleet status data since MQTT connection is interrupted.");
+            return false;
+        }
+        self.record.sequenceNumber += 1;
+        details.sequenceNumber = self.record.sequenceNumber;
+        if details.messageType == MessageType::COMPLETE {
+            self.record.lastPeriodicUpdateTime = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64);
+        }
+        if let Err(e) = self.record.save(&self.path) {
+            warn!("{:#}", e);
+        }
+        match publish_fleet_status(&self.mqtt_client, details).await {
+            std::result::Result::Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to publish fleet status: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Publish `details`, split into as many messages as needed to stay under the size limit.
+async fn publish_fleet_status(mqtt_client: &MqttClient, details: FleetStatusDetails) -> Result<()> {
+    let topic = DEFAULT_FLEET_STATUS_SERVICE_PUBLISH_TOPIC.replace("{thing_name}", &details.thing);
+    let payloads = chunk_payloads(details)?;
+    let total = payloads.len();
+    for payload in payloads {
+        mqtt_client
+            .publish(PublishRequest::new(topic.as_str(), payload))
+            .await?;
+    }
+    info!(
+        event = "fss-status-update-published",
+        chunks = total,
+        "Status update published to FSS"
+    );
+    Ok(())
+}
+
+/**
+ * Serialize the fleet status into payloads under `MAX_PAYLOAD_LENGTH_BYTES`.
+ *
+ * Every payload carries the common fields, a slice of the components and its position in the
+ * update, so the cloud can reassemble the full status.
+ */
+pub fn chunk_payloads(mut details: FleetStatusDetails) -> Result<Vec<String>> {
+    let components = std::mem::take(&mut details.components);
+    // Reserve room for the largest chunk info this update can carry.
+    let most_chunks = components.len().max(1);
+    details.chunkInfo = Some(ChunkInfo {
+        chunkId: most_chunks,
+        totalChunks: most_chunks,
+    });
+    let base_size = serde_json::to_vec(&details)?.len();
+    let chunks = chunked::chunk_by_size(components, base_size, MAX_PAYLOAD_LENGTH_BYTES)?;
+
+    let total_chunks = chunks.len();
+    let mut payloads = vec![];
+    for (index, components) in chunks.into_iter().enumerate() {
+        details.chunkInfo = Some(ChunkInfo {
+            chunkId: index + 1,
+            totalChunks: total_chunks,
+        });
+        details.components = components;
+        payloads.push(serde_json::to_string(&details)?);
+    }
+    Ok(payloads)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FleetStatusDetails {
-    ggcVersion: &'static str,
-    platform: &'static str,
-    architecture: &'static str,
+    ggcVersion: String,
+    platform: String,
+    architecture: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    architectureDetail: String,
     thing: String,
     overallDeviceStatus: OverallStatus,
-    sequence_number: usize,
+    sequenceNumber: u64,
+    messageType: MessageType,
+    trigger: Trigger,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunkInfo: Option<ChunkInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deploymentInformation: Option<DeploymentInformation>,
     pub components: Vec<ServiceStatus>,
 }
 
+/// Position of a message within a chunked fleet status update, counting from 1.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkInfo {
+    chunkId: usize,
+    totalChunks: usize,
+}
+
 impl FleetStatusDetails {
     pub fn new(name: &str) -> Self {
         FleetStatusDetails {
-            ggcVersion: kernel::VERSION,
-            platform: "linux",
-            architecture: "x86_64",
+            ggcVersion: kernel::version(),
+            platform: PLATFORM.os.clone(),
+            architecture: PLATFORM.architecture.clone(),
+            architectureDetail: PLATFORM.architecture_detail.clone(),
             thing: name.to_string(),
             overallDeviceStatus: OverallStatus::HEALTHY,
-            sequence_number: 9,
-            // deploymentInformation: "".to_string(),
+            sequenceNumber: 0,
+            messageType: MessageType::COMPLETE,
+            trigger: Trigger::Cadence,
+            chunkInfo: None,
+            deploymentInformation: None,
             components: vec![],
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum OverallStatus {
     HEALTHY,
     UNHEALTHY,
 }
 
-// pub const detailedStatus: &str = "detailedStatus";
-// pub const failureCause: &str = "failureCause";
+impl OverallStatus {
+    /// The device is unhealthy as soon as one component is `BROKEN` or `ERRORED`.
+    pub fn of<'a>(states: impl IntoIterator<Item = &'a State>) -> Self {
+        let unhealthy = states
+            .into_iter()
+            .any(|state| matches!(state, State::BROKEN | State::ERRORED));
+        if unhealthy {
+            OverallStatus::UNHEALTHY
+        } else {
+            OverallStatus::HEALTHY
+        }
+    }
+}
+
+/// Whether an update carries every component or only the ones that changed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    COMPLETE,
+    PARTIAL,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct StatusDetails {
-    pub DETAILED_STATUS_KEY: String,
-    pub FAILURE_CAUSE_KEY: String,
+/// What caused a fleet status update.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Trigger {
+    NucleusLaunch,
+    Cadence,
+    ThingDeployment,
+    ThingGroupDeployment,
+    LocalDeployment,
+    BrokenComponent,
 }
 
-// pub const STATUS_KEY: &str = "status";
-// pub const STATUS_DETAILS_KEY: &str = "status_details";
-// pub const ARN_FOR_STATUS_KEY: &str = "fleetConfigurationArnForStatus";
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StatusDetails {
+    #[serde(rename = "detailedStatus", skip_serializing_if = "Option::is_none")]
+    pub detailed_status: Option<String>,
+    #[serde(rename = "failureCause", skip_serializing_if = "Option::is_none")]
+    pub failure_cause: Option<String>,
+}
+
+/// Outcome of a deployment, attached to the update sent when it completes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DeploymentInformation {
-    status: String,
-    status_details: StatusDetails,
-    fleetConfigurationArnForStatus: String,
+    pub status: String,
+    #[serde(rename = "statusDetails")]
+    pub status_details: StatusDetails,
+    pub fleetConfigurationArnForStatus: String,
 }
 
+impl DeploymentInformation {
+    pub fn new(status: &str, arn: &str) -> Self {
+        DeploymentInformation {
+            status: status.to_string(),
+            status_details: StatusDetails::default(),
+            fleetConfigurationArnForStatus: arn.to_string(),
+        }
+    }
+
+    pub fn detailed_status(mut self, detailed_status: &str) -> Self {
+        self.status_details.detailed_status = Some(detailed_status.to_string());
+        self
+    }
+
+    pub fn failure_cause(mut self, failure_cause: &str) -> Self {
+        self.status_details.failure_cause = Some(failure_cause.to_string());
+        self
+    }
+}
+
+/// Deployment status changes, consumed by the fleet status service task.
+static DEPLOYMENT_STATUS: Lazy<broadcast::Sender<(Trigger, DeploymentInformation)>> =
+    Lazy::new(|| broadcast::channel(16).0);
+
+pub const DEPLOYMENT_IN_PROGRESS: &str = "IN_PROGRESS";
+pub const DEPLOYMENT_SUCCEEDED: &str = "SUCCEEDED";
+pub const DEPLOYMENT_FAILED: &str = "FAILED";
 
 pub const FLEET_STATUS_SERVICE_TOPICS: &str = "FleetStatusService";
 pub const DEFAULT_FLEET_STATUS_SERVICE_PUBLISH_TOPIC: &str =
@@ -159,8 +459,7 @@ pub const DEFAULT_FLEET_STATUS_SERVICE_PUBLISH_TOPIC: &str =
 pub const FLEET_STATUS_TEST_PERIODIC_UPDATE_INTERVAL_SEC: &str = "fssPeriodicUpdateIntervalSec";
 pub const DEFAULT_PERIODIC_PUBLISH_INTERVAL_SEC: usize = 86_400;
 pub const FLEET_STATUS_PERIODIC_PUBLISH_INTERVAL_SEC: &str = "periodicStatusPublishIntervalSeconds";
-const FLEET_STATUS_SEQUENCE_NUMBER_TOPIC: &str = "sequenceNumber";
-const FLEET_STATUS_LAST_PERIODIC_UPDATE_TIME_TOPIC: &str = "lastPeriodicUpdateTime";
+const FLEET_STATUS_RECORD_FILE: &str = "fleetStatus.json";
 const MAX_PAYLOAD_LENGTH_BYTES: usize = 128_000;
 pub const DEVICE_OFFLINE_MESSAGE: &str =
     "Device not configured to talk to AWS IoT cloud. FleetStatusService is offline";
@@ -191,45 +490,164 @@ pub struct FleetStatusService {
     // ScheduledFuture<?> periodicUpdateFuture,
 }
 
+/**
+ * Collect the status of the components in `updated`, or of every component when `None`.
+ */
 pub fn fss_data(
-    name: &str, // overAllStatus: OverallStatus,
-                // deploymentInformation: DeploymentInformation,
+    name: &str,
+    trigger: Trigger,
+    updated: Option<&HashSet<String>>,
 ) -> FleetStatusDetails {
-    // if (!isConnected.get()) {
-    // if true {
-    //     info!("Not updating fleet status data since MQTT connection is interrupted.");
-    //     return;
-    // }
-    let mut components: ServiceStatus;
-    let sequence_number: usize;
-
-    // synchronized (greengrassServiceSet)
-
     let mut payload = FleetStatusDetails::new(name);
+    payload.trigger = trigger;
+    if updated.is_some() {
+        payload.messageType = MessageType::PARTIAL;
+    }
+    // The overall status covers every component, even in a partial update.
+    let states: Vec<State> = SERVICES.iter().map(|r| r.status().clone()).collect();
+    payload.overallDeviceStatus = OverallStatus::of(&states);
     SERVICES
         .iter()
+        .filter(|r| updated.is_none_or(|updated| updated.contains(r.key())))
         .for_each(|r| payload.components.push(r.value().clone()));
-    // let fleetStatusDetails = FleetStatusDetails::new();
-    // let serde_string = serde_json::to_string(&fleetStatusDetails).unwrap();
-    // info!(
-    //     event = "fss-status-update-published",
-    //     "fleetStatusDetails {:?}", serde_string
-    // );
-    // info!("fss-status-update-published").log("fleetStatusDetails {} components {}");
-    // fleetStatusDetails, components);
-
-    // publisher.publish(fleetStatusDetails, components);
-
-    // println!("{}", json!(payload));
     payload
-
-    // serde_string
 }
 
+/**
+ * Report a deployment status change. An update with the components that changed during the
+ * deployment is published once the deployment is no longer `IN_PROGRESS`.
+ */
 #[doc(alias = "deploymentStatusChanged")]
-pub fn deployment_status_changed(
-    name: &str, // overAllStatus: OverallStatus,
-                // deploymentInformation: DeploymentInformation,
-) -> bool {
+pub fn deployment_status_changed(trigger: Trigger, information: DeploymentInformation) -> bool {
+    // Nothing to do when the fleet status service is not running.
+    let _ = DEPLOYMENT_STATUS.send((trigger, information));
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_large_fleet_status() {
+        let mut details = FleetStatusDetails::new("thing");
+        for _ in 0..2000 {
+            details.components.push(<Status as Service>::new(
+                "aws.greengrass.SomeComponentWithALongName",
+                "1.0.0",
+            ));
+        }
+        let payloads = chunk_payloads(details).unwrap();
+        assert!(payloads.len() > 1);
+
+        let mut components = 0;
+        for (index, payload) in payloads.iter().enumerate() {
+            assert!(payload.len() <= MAX_PAYLOAD_LENGTH_BYTES);
+            let chunk: serde_json::Value = serde_json::from_str(payload).unwrap();
+            assert_eq!(chunk["thing"], "thing");
+            assert_eq!(chunk["chunkInfo"]["chunkId"], index + 1);
+            assert_eq!(chunk["chunkInfo"]["totalChunks"], payloads.len());
+            components += chunk["components"].as_array().unwrap().len();
+        }
+        assert_eq!(components, 2000);
+    }
+
+    #[test]
+    fn partial_update_only_has_changed_components() {
+        SERVICES.insert(
+            "fss.test.Changed".to_string(),
+            <Status as Service>::new("fss.test.Changed", "1.0.0"),
+        );
+        SERVICES.insert(
+            "fss.test.Unchanged".to_string(),
+            <Status as Service>::new("fss.test.Unchanged", "1.0.0"),
+        );
+        let updated = HashSet::from(["fss.test.Changed".to_string()]);
+        let mut details = fss_data("thing", Trigger::ThingDeployment, Some(&updated));
+        details.deploymentInformation = Some(
+            DeploymentInformation::new(DEPLOYMENT_SUCCEEDED, "arn").detailed_status("SUCCESSFUL"),
+        );
+
+        let payload = serde_json::to_value(&details).unwrap();
+        assert_eq!(payload["messageType"], "PARTIAL");
+        assert_eq!(payload["trigger"], "THING_DEPLOYMENT");
+        assert_eq!(payload["components"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            payload["components"][0]["componentName"],
+            "fss.test.Changed"
+        );
+        assert_eq!(
+            payload["deploymentInformation"]["statusDetails"]["detailedStatus"],
+            "SUCCESSFUL"
+        );
+        assert!(payload["deploymentInformation"]["statusDetails"]
+            .get("failureCause")
+            .is_none());
+    }
+
+    #[test]
+    fn update_record_survives_restart() {
+        let path = std::env::temp_dir().join(format!("fss-record-{}.json", std::process::id()));
+        let record = UpdateRecord {
+            sequenceNumber: 42,
+            lastPeriodicUpdateTime: 1_000_000,
+        };
+        record.save(&path).unwrap();
+        assert_eq!(UpdateRecord::load(&path), record);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(UpdateRecord::load(&path), UpdateRecord::default());
+
+        let last = UNIX_EPOCH + Duration::from_millis(record.lastPeriodicUpdateTime);
+        let period = Duration::from_secs(60);
+        assert_eq!(
+            record.until_periodic_update(period, Duration::ZERO, last + Duration::from_secs(59)),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            record.until_periodic_update(period, Duration::ZERO, last + period),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn schedules_periodic_updates_after_restart() {
+        let hour = Duration::from_secs(3600);
+        let period = 24 * hour;
+        let jitter = hour / 2;
+        let now = UNIX_EPOCH + 1000 * hour;
+        let updated_at = |time: SystemTime| UpdateRecord {
+            sequenceNumber: 1,
+            lastPeriodicUpdateTime: time.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+        };
+
+        // Restarted 23 hours after the last update: due in an hour, not a period later.
+        let record = updated_at(now - 23 * hour);
+        assert_eq!(record.until_periodic_update(period, jitter, now), hour);
+        // Overdue, or never updated: after the jitter.
+        let record = updated_at(now - 30 * hour);
+        assert_eq!(record.until_periodic_update(period, jitter, now), jitter);
+        assert_eq!(
+            UpdateRecord::default().until_periodic_update(period, jitter, now),
+            jitter
+        );
+        // Just updated, for instance on launch: a period later.
+        let record = updated_at(now);
+        assert_eq!(record.until_periodic_update(period, jitter, now), period);
+    }
+
+    #[test]
+    fn overall_status_follows_components() {
+        assert_eq!(
+            OverallStatus::of(&[State::RUNNING, State::FINISHED]),
+            OverallStatus::HEALTHY
+        );
+        assert_eq!(
+            OverallStatus::of(&[State::RUNNING, State::BROKEN]),
+            OverallStatus::UNHEALTHY
+        );
+        assert_eq!(
+            OverallStatus::of(&[State::ERRORED]),
+            OverallStatus::UNHEALTHY
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{Error, Result};
-use rumqttc::{self, AsyncClient, Key, MqttOptions, QoS, Transport};
+use rumqttc::{self, AsyncClient, MqttOptions, QoS, Transport};
 use tokio::{task, time};
 
 /**